@@ -25,9 +25,16 @@ export const recipe = {
             tarball: `https://ftp.gnu.org/gnu/glibc/glibc-2.34.tar.gz`,
         },
         dependencies: {
+            "brioche-bootstrap-phase1-linux-headers": "5.13.12",
+        },
+        buildDependencies: {
             "brioche-bootstrap-phase1-binutils": "2.37",
             "brioche-bootstrap-phase1-gcc": "2.37",
-            "brioche-bootstrap-phase1-linux-headers": "5.13.12",
+        },
+        conflicts: {
+            // Both binutils and gcc install `tools/share/info/dir`
+            policy: "priority",
+            priority: ["brioche-bootstrap-phase1-gcc"],
         },
         build: sh`
             set -eu
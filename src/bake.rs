@@ -1,12 +1,14 @@
 use std::{
-    io::{BufRead as _, Write as _},
+    io::BufRead as _,
     path::PathBuf,
     sync::{atomic::AtomicU64, Arc},
+    time::Duration,
 };
 
-use tokio::fs;
-
 use crate::{
+    bootstrap_env::Command,
+    event::{Event, LogStream, MessageLevel},
+    format::format_duration,
     recipe::{ResolvedRecipeRef, ResolvedRecipeSet},
     state::State,
 };
@@ -16,60 +18,237 @@ pub struct BakedRecipe {
     pub prefix_path: PathBuf,
 }
 
+#[derive(Debug, Default, Clone)]
+pub struct BakeOptions {
+    /// Keep the work dir of a failed build instead of removing it.
+    pub keep_failed: bool,
+    /// Start an interactive shell in the build environment when a build
+    /// fails.
+    pub debug_on_failure: bool,
+    /// How long each build script can run, for recipes that don't set their
+    /// own timeout.
+    pub timeout: Option<Duration>,
+    pub limits: crate::limits::ResourceLimits,
+    pub sandbox_profile: crate::sandbox::SandboxProfile,
+}
+
+/// Returned when a build script runs for longer than its timeout.
+#[derive(Debug)]
+pub struct TimedOut {
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "build timed out after {}", format_duration(self.timeout))
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
 #[async_recursion::async_recursion]
 pub async fn get_baked_recipe(
     state: &State,
     recipe_set: &ResolvedRecipeSet,
     recipe_ref: &ResolvedRecipeRef,
+    options: &BakeOptions,
 ) -> anyhow::Result<BakedRecipe> {
     let recipe = recipe_set.get(recipe_ref);
     if let Some(prefix_path) = state.get_recipe_output(recipe_ref)? {
-        println!("Recipe {} {} already baked", recipe.name, recipe.version);
+        state.emit(Event::CacheHit {
+            recipe_ref: *recipe_ref,
+            name: recipe.name.clone(),
+            version: recipe.version.clone(),
+        });
         return Ok(BakedRecipe {
             recipe_ref: *recipe_ref,
             prefix_path,
         });
     }
 
-    let bootstrap_env = crate::bootstrap_env::BootstrapEnv::new(&state).await?;
-    let recipe_prefix = bootstrap_env.recipe_prefix_path();
+    let dependency_recipes = get_baked_dependencies(state, recipe_set, recipe_ref, options).await?;
+
+    let mut bootstrap_env = crate::bootstrap_env::BootstrapEnv::new(state).await?;
+
+    let updated = state.persist_lockfile().await?;
+    state.emit(Event::LockfileUpdated { updated });
 
-    match state.persist_lockfile().await? {
-        true => {
-            println!("Updated lockfile");
+    state.emit(Event::BuildStarted {
+        recipe_ref: *recipe_ref,
+        name: recipe.name.clone(),
+        version: recipe.version.clone(),
+    });
+    let build_start = std::time::Instant::now();
+
+    let bake_result = bake_in_env(
+        state,
+        recipe_set,
+        recipe_ref,
+        &dependency_recipes,
+        options,
+        &mut bootstrap_env,
+    )
+    .await;
+    let prefix_path = match bake_result {
+        Ok(prefix_path) => {
+            state.emit(Event::BuildFinished {
+                recipe_ref: *recipe_ref,
+                name: recipe.name.clone(),
+                version: recipe.version.clone(),
+                duration: build_start.elapsed(),
+                prefix_path: prefix_path.clone(),
+            });
+            prefix_path
         }
-        false => {
-            println!("Lockfile already up to date");
+        Err(error) => {
+            state.emit(Event::BuildFailed {
+                recipe_ref: *recipe_ref,
+                name: recipe.name.clone(),
+                version: recipe.version.clone(),
+                duration: build_start.elapsed(),
+                error: format!("{:#}", error),
+            });
+
+            if options.debug_on_failure {
+                state.emit(Event::Message {
+                    level: MessageLevel::Info,
+                    message: format!(
+                        "Starting debug shell for {} {}, exit the shell to continue",
+                        recipe.name, recipe.version
+                    ),
+                    details: vec![format!("{:#}", error)],
+                });
+                if let Err(shell_error) = run_shell(state, &bootstrap_env).await {
+                    state.emit(Event::Message {
+                        level: MessageLevel::Warning,
+                        message: format!("failed to run debug shell: {:#}", shell_error),
+                        details: vec![],
+                    });
+                }
+            }
+
+            if options.keep_failed {
+                let work_dir = bootstrap_env.keep_work_dir();
+                state.emit(Event::Message {
+                    level: MessageLevel::Info,
+                    message: format!(
+                        "Kept work dir for failed recipe {} {}: {}",
+                        recipe.name,
+                        recipe.version,
+                        work_dir.display()
+                    ),
+                    details: vec![],
+                });
+            }
+
+            return Err(error);
         }
-    }
+    };
 
-    for dependency_ref in &recipe.dependencies {
-        let dependency_recipe = get_baked_recipe(state, recipe_set, dependency_ref).await?;
+    let updated = state.persist_lockfile().await?;
+    state.emit(Event::LockfileUpdated { updated });
 
-        // Copy each entry from the recipe into the prefix path
+    Ok(BakedRecipe {
+        recipe_ref: *recipe_ref,
+        prefix_path,
+    })
+}
 
-        let mut cp_command = tokio::process::Command::new("cp");
-        cp_command.arg("-a");
-        cp_command.arg("-r");
+/// Start an interactive shell for a recipe, in the same environment the
+/// recipe would be baked in (with its dependencies baked and its source
+/// unpacked).
+pub async fn shell(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    options: &BakeOptions,
+) -> anyhow::Result<()> {
+    let recipe = recipe_set.get(recipe_ref);
+    let dependency_recipes = get_baked_dependencies(state, recipe_set, recipe_ref, options).await?;
+
+    let mut bootstrap_env = crate::bootstrap_env::BootstrapEnv::new(state).await?;
+    bootstrap_env.set_sandbox_profile(options.sandbox_profile);
+    prepare_env(
+        state,
+        recipe_set,
+        recipe_ref,
+        &dependency_recipes,
+        &mut bootstrap_env,
+    )
+    .await?;
+
+    state.emit(Event::Message {
+        level: MessageLevel::Info,
+        message: format!(
+            "Starting shell for recipe {} {}",
+            recipe.name, recipe.version
+        ),
+        details: vec![],
+    });
+    run_shell(state, &bootstrap_env).await?;
 
-        let mut entries = fs::read_dir(&dependency_recipe.prefix_path).await?;
-        while let Some(entry) = entries.next_entry().await? {
-            cp_command.arg(&entry.path());
-        }
+    Ok(())
+}
 
-        cp_command.arg(&recipe_prefix.host_input_path);
+/// Bake every recipe that should be available in a recipe's build
+/// environment (see `ResolvedRecipeSet::env_dependencies`).
+pub async fn get_baked_dependencies(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    options: &BakeOptions,
+) -> anyhow::Result<Vec<BakedRecipe>> {
+    let mut dependency_recipes = vec![];
+    for dependency_ref in &recipe_set.env_dependencies(recipe_ref) {
+        let dependency_recipe =
+            get_baked_recipe(state, recipe_set, dependency_ref, options).await?;
+        dependency_recipes.push(dependency_recipe);
+    }
 
-        let cp_result = cp_command.spawn()?.wait().await?;
-        if !cp_result.success() {
-            anyhow::bail!(
-                "failed to copy dependency {} from {} to {}",
-                dependency_ref,
-                dependency_recipe.prefix_path.display(),
-                recipe_prefix.host_input_path.display(),
-            );
-        }
+    Ok(dependency_recipes)
+}
+
+/// Layer a recipe's baked dependencies into a bootstrap env, resolving
+/// conflicts between them using the recipe's conflict policy.
+pub async fn add_dependency_layers(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    dependency_recipes: &[BakedRecipe],
+    bootstrap_env: &mut crate::bootstrap_env::BootstrapEnv,
+) -> anyhow::Result<()> {
+    let recipe = recipe_set.get(recipe_ref);
+
+    let dependency_layers =
+        crate::conflicts::order_dependency_layers(state, recipe_set, recipe, dependency_recipes)
+            .await?;
+    for dependency_recipe in dependency_layers {
+        bootstrap_env
+            .add_dependency_layer(&dependency_recipe.prefix_path)
+            .await?;
     }
 
+    Ok(())
+}
+
+async fn prepare_env(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    dependency_recipes: &[BakedRecipe],
+    bootstrap_env: &mut crate::bootstrap_env::BootstrapEnv,
+) -> anyhow::Result<()> {
+    let recipe = recipe_set.get(recipe_ref);
+
+    add_dependency_layers(
+        state,
+        recipe_set,
+        recipe_ref,
+        dependency_recipes,
+        bootstrap_env,
+    )
+    .await?;
+
     let host_source_path = bootstrap_env.host_source_path();
     let source = recipe_set.get_source(&recipe.source);
     match &source {
@@ -83,7 +262,8 @@ pub async fn get_baked_recipe(
             let cp_result = cp_command.spawn()?.wait().await?;
             if !cp_result.success() {
                 anyhow::bail!(
-                    "failed to copy git source from {} to {}",
+                    "failed to copy git source for commit {} from {} to {}",
+                    git_checkout.commit,
                     git_checkout.checkout_path.display(),
                     host_source_path.display(),
                 );
@@ -97,29 +277,120 @@ pub async fn get_baked_recipe(
         }
     }
 
-    let mut command = crate::bootstrap_env::Command::new("/bin/sh");
-    command.current_dir(bootstrap_env.container_source_path());
-    command.env("BRIOCHE_PREFIX", &recipe_prefix.container_path);
-    command.env("BRIOCHE_BOOTSTRAP_TARGET", bootstrap_env.bootstrap_target());
+    Ok(())
+}
+
+/// Bake a recipe that has already been baked again, without saving the
+/// result. Returns the path of the new output prefix, which lives in the
+/// bootstrap env's work dir.
+pub async fn rebake_in_env(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    options: &BakeOptions,
+    bootstrap_env: &mut crate::bootstrap_env::BootstrapEnv,
+) -> anyhow::Result<PathBuf> {
+    let dependency_recipes = get_baked_dependencies(state, recipe_set, recipe_ref, options).await?;
+
+    let build_output = build_in_env(
+        state,
+        recipe_set,
+        recipe_ref,
+        &dependency_recipes,
+        options,
+        bootstrap_env,
+    )
+    .await?;
+
+    Ok(build_output.output_path)
+}
+
+async fn bake_in_env(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    dependency_recipes: &[BakedRecipe],
+    options: &BakeOptions,
+    bootstrap_env: &mut crate::bootstrap_env::BootstrapEnv,
+) -> anyhow::Result<PathBuf> {
+    let recipe = recipe_set.get(recipe_ref);
 
+    let build_output = build_in_env(
+        state,
+        recipe_set,
+        recipe_ref,
+        dependency_recipes,
+        options,
+        bootstrap_env,
+    )
+    .await?;
+
+    let build_metadata = crate::state::BuildMetadata {
+        baked_at: std::time::SystemTime::now(),
+        content_hash: None,
+        aux: Some(build_output.recipe_aux),
+    };
+    let prefix_path = state
+        .save_recipe_output(
+            recipe_ref,
+            recipe,
+            build_metadata,
+            &build_output.output_path,
+        )
+        .await?;
+
+    Ok(prefix_path)
+}
+
+struct BuildOutput {
+    output_path: PathBuf,
+    recipe_aux: crate::state::RecipeAux,
+}
+
+/// Run a recipe's build script, returning the output prefix with files from
+/// its dependencies excluded.
+async fn build_in_env(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    dependency_recipes: &[BakedRecipe],
+    options: &BakeOptions,
+    bootstrap_env: &mut crate::bootstrap_env::BootstrapEnv,
+) -> anyhow::Result<BuildOutput> {
+    let recipe = recipe_set.get(recipe_ref);
+    let recipe_prefix = bootstrap_env.recipe_prefix_path();
+
+    prepare_env(
+        state,
+        recipe_set,
+        recipe_ref,
+        dependency_recipes,
+        bootstrap_env,
+    )
+    .await?;
+
+    let command = build_command(bootstrap_env);
+
+    let timeout = match recipe.build.timeout {
+        Some(timeout_secs) => Some(Duration::from_secs(timeout_secs.into())),
+        None => options.timeout,
+    };
+
+    bootstrap_env.set_resource_limits(options.limits.clone());
+    bootstrap_env.set_sandbox_profile(options.sandbox_profile);
     let mut child = bootstrap_env.spawn(&command)?;
+    let cgroup = child.take_cgroup();
+    let rlimits = child.take_rlimits();
+
+    // Kill the sandbox if it times out, goes over its disk limit, or if
+    // baking gets cancelled (which drops this future)
+    let mut kill_guard = child.kill_guard();
     let child_stdin = child.take_stdin();
     let child_stdout = child.take_stdout();
     let child_stderr = child.take_stderr();
 
-    let child_task = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
-        let exit_status = child.wait()?;
-
-        match exit_status {
-            unshare::ExitStatus::Exited(0) => Ok(()),
-            unshare::ExitStatus::Exited(exit_code) => {
-                anyhow::bail!("process exited with code {}", exit_code);
-            }
-            unshare::ExitStatus::Signaled(signal, _) => {
-                anyhow::bail!("process exited with signal {}", signal.as_str());
-            }
-        }
-    });
+    let script_start = std::time::Instant::now();
+    let mut child_task = tokio::task::spawn_blocking(move || child.wait_with_usage());
 
     let recipe_build = recipe.build.clone();
     let lines_stdout = Arc::new(AtomicU64::new(0));
@@ -137,6 +408,8 @@ pub async fn get_baked_recipe(
     });
     let child_stdout_task = tokio::task::spawn_blocking({
         let lines_stdout = lines_stdout.clone();
+        let events = state.events();
+        let recipe_ref = *recipe_ref;
         move || -> anyhow::Result<_> {
             let child_stdout = match child_stdout {
                 Some(child_stdout) => child_stdout,
@@ -146,14 +419,16 @@ pub async fn get_baked_recipe(
             };
             let child_stdout = std::io::BufReader::new(child_stdout);
 
-            let stdout = std::io::stdout();
-            let mut stdout = stdout.lock();
             for line in child_stdout.split(b'\n') {
                 let line = line?;
                 let line = String::from_utf8_lossy(&line);
 
                 lines_stdout.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                writeln!(stdout, "{}", line)?;
+                events.emit(Event::LogLine {
+                    recipe_ref,
+                    stream: LogStream::Stdout,
+                    line: line.into_owned(),
+                });
             }
 
             Ok(())
@@ -161,6 +436,8 @@ pub async fn get_baked_recipe(
     });
     let child_stderr_task = tokio::task::spawn_blocking({
         let lines_stderr = lines_stderr.clone();
+        let events = state.events();
+        let recipe_ref = *recipe_ref;
         move || -> anyhow::Result<_> {
             let child_stderr = match child_stderr {
                 Some(child_stderr) => child_stderr,
@@ -170,55 +447,213 @@ pub async fn get_baked_recipe(
             };
             let child_stderr = std::io::BufReader::new(child_stderr);
 
-            let stderr = std::io::stderr();
-            let mut stderr = stderr.lock();
             for line in child_stderr.split(b'\n') {
                 let line = line?;
                 let line = String::from_utf8_lossy(&line);
 
                 lines_stderr.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                writeln!(stderr, "{}", line)?;
+                events.emit(Event::LogLine {
+                    recipe_ref,
+                    stream: LogStream::Stderr,
+                    line: line.into_owned(),
+                });
             }
 
             Ok(())
         }
     });
 
-    let (child_task, child_stdin_task, child_stdout_task, child_stderr_task) = tokio::try_join!(
-        child_task,
-        child_stdin_task,
-        child_stdout_task,
-        child_stderr_task
-    )?;
-
-    let () = child_task?;
+    let timeout_expired = async {
+        match timeout {
+            Some(timeout) => {
+                tokio::time::sleep(timeout).await;
+                timeout
+            }
+            None => std::future::pending().await,
+        }
+    };
+    let child_result = tokio::select! {
+        child_result = &mut child_task => child_result?,
+        timeout = timeout_expired => {
+            kill_guard.kill()?;
+            let _ = child_task.await;
+            return Err(TimedOut { timeout }.into());
+        }
+        limit_exceeded = bootstrap_env.wait_for_disk_limit() => {
+            let limit_exceeded = limit_exceeded?;
+            kill_guard.kill()?;
+            let _ = child_task.await;
+            return Err(limit_exceeded.into());
+        }
+    };
+    kill_guard.disarm();
+    let wall_time = script_start.elapsed();
+
+    let (child_stdin_task, child_stdout_task, child_stderr_task) =
+        tokio::try_join!(child_stdin_task, child_stdout_task, child_stderr_task)?;
+
+    let (exit_status, usage) = child_result?;
+    if !exit_status.success() {
+        // Report a limit the build went over instead of whatever error it
+        // failed with as a result
+        if let Some(cgroup) = &cgroup {
+            cgroup.check_limits()?;
+        }
+        if let unshare::ExitStatus::Signaled(signal, _) = exit_status {
+            crate::limits::check_exit_signal(&options.limits, signal as i32)?;
+        }
+    }
+    let exit_error = match exit_status {
+        unshare::ExitStatus::Exited(0) => None,
+        unshare::ExitStatus::Exited(exit_code) => {
+            Some(anyhow::anyhow!("process exited with code {}", exit_code))
+        }
+        unshare::ExitStatus::Signaled(signal, _) => Some(anyhow::anyhow!(
+            "process exited with signal {}",
+            signal.as_str()
+        )),
+    };
+    if let Some(exit_error) = exit_error {
+        let note = rlimits
+            .as_ref()
+            .and_then(crate::limits::rlimit_failure_note);
+        match note {
+            Some(note) => return Err(exit_error.context(note)),
+            None => return Err(exit_error),
+        }
+    }
     let () = child_stdin_task?;
     let () = child_stdout_task?;
     let () = child_stderr_task?;
 
-    let prefix_path = state
-        .save_recipe_output(&recipe_ref, &recipe_prefix.host_output_path)
-        .await?;
+    bootstrap_env.reset_ownership().await?;
+
+    // The build may not have written anything to the prefix at all
+    tokio::fs::create_dir_all(&recipe_prefix.host_output_path).await?;
+
+    let overridden_dependency_paths = crate::output::exclude_dependency_files(
+        &recipe_prefix.host_output_path,
+        &bootstrap_env.dependency_prefixes(),
+        bootstrap_env.mount_backend(),
+    )
+    .await?;
+    if !overridden_dependency_paths.is_empty() {
+        state.emit(Event::Message {
+            level: MessageLevel::Info,
+            message: format!(
+                "The output of {} {} overrides {} files from its dependencies:",
+                recipe.name,
+                recipe.version,
+                overridden_dependency_paths.len()
+            ),
+            details: display_paths(&overridden_dependency_paths),
+        });
+    }
+
+    let store_prefix_path = state.recipe_output_path(recipe_ref);
+    let relocation_report = crate::relocate::relocate_output(
+        &recipe_prefix.host_output_path,
+        &recipe_prefix.container_path,
+        &store_prefix_path,
+        &bootstrap_env.dependency_prefixes(),
+    )
+    .await?;
+    if !relocation_report.unrelocated_paths.is_empty() {
+        state.emit(Event::Message {
+            level: MessageLevel::Warning,
+            message: format!(
+                "{} files in the output of {} {} still reference the build prefix {}:",
+                relocation_report.unrelocated_paths.len(),
+                recipe.name,
+                recipe.version,
+                recipe_prefix.container_path.display()
+            ),
+            details: display_paths(&relocation_report.unrelocated_paths),
+        });
+    }
+    if !relocation_report.long_shebang_paths.is_empty() {
+        state.emit(Event::Message {
+            level: MessageLevel::Warning,
+            message: format!(
+                "relocated shebangs in the output of {} {} may be too long for the kernel:",
+                recipe.name, recipe.version
+            ),
+            details: display_paths(&relocation_report.long_shebang_paths),
+        });
+    }
+
+    crate::output::normalize_output_metadata(&recipe_prefix.host_output_path).await?;
+    let (output_size_bytes, output_file_count) =
+        crate::output::output_stats(&recipe_prefix.host_output_path).await?;
 
     let lines_stdout = lines_stdout.load(std::sync::atomic::Ordering::SeqCst);
     let lines_stderr = lines_stderr.load(std::sync::atomic::Ordering::SeqCst);
     let recipe_aux = crate::state::RecipeAux {
         lines_stdout,
         lines_stderr,
+        wall_time,
+        cpu_time: usage.cpu_time,
+        max_process_rss_bytes: usage.max_process_rss_bytes,
+        cgroup_peak_memory_bytes: cgroup
+            .as_ref()
+            .and_then(|cgroup| cgroup.peak_memory_bytes()),
+        output_size_bytes,
+        output_file_count,
+        host_triple: target_lexicon::HOST.to_string(),
+        brioche_version: env!("CARGO_PKG_VERSION").to_string(),
     };
-    state.set_recipe_aux(recipe_ref, recipe_aux).await;
 
-    match state.persist_lockfile().await? {
-        true => {
-            println!("Updated lockfile");
+    Ok(BuildOutput {
+        output_path: recipe_prefix.host_output_path,
+        recipe_aux,
+    })
+}
+
+/// Build the command used to run a recipe's build script. The script itself
+/// is written to the shell's stdin.
+pub fn build_command(bootstrap_env: &crate::bootstrap_env::BootstrapEnv) -> Command {
+    let recipe_prefix = bootstrap_env.recipe_prefix_path();
+
+    let mut command = Command::new("/bin/sh");
+    command.current_dir(bootstrap_env.container_source_path());
+    command.env("BRIOCHE_PREFIX", &recipe_prefix.container_path);
+    command.env("BRIOCHE_BOOTSTRAP_TARGET", bootstrap_env.bootstrap_target());
+    command
+}
+
+fn display_paths(paths: &[PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect()
+}
+
+/// Run an interactive shell attached to the current terminal, using the
+/// same working directory and environment variables as the build script.
+async fn run_shell(
+    state: &State,
+    bootstrap_env: &crate::bootstrap_env::BootstrapEnv,
+) -> anyhow::Result<()> {
+    let mut command = build_command(bootstrap_env);
+    command.arg("-i");
+    command.inherit_stdio();
+
+    let child = bootstrap_env.spawn(&command)?;
+    let exit_status = child.wait_interactive().await?;
+
+    let message = match exit_status {
+        unshare::ExitStatus::Exited(exit_code) => {
+            format!("Shell exited with code {}", exit_code)
         }
-        false => {
-            println!("Lockfile already up to date");
+        unshare::ExitStatus::Signaled(signal, _) => {
+            format!("Shell exited with signal {}", signal.as_str())
         }
-    }
+    };
+    state.emit(Event::Message {
+        level: MessageLevel::Info,
+        message,
+        details: vec![],
+    });
 
-    Ok(BakedRecipe {
-        recipe_ref: *recipe_ref,
-        prefix_path,
-    })
+    Ok(())
 }
@@ -2,35 +2,85 @@ use std::{
     collections::HashMap,
     ffi::{OsStr, OsString},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Once,
+    },
 };
 
 use hex_literal::hex;
 use joinery::JoinableIterator;
 use tokio::fs;
 
-use crate::{hash::Hash, state::State};
+use crate::{
+    event::{Event, EventSink, MessageLevel},
+    hash::Hash,
+    id_map::IdMapping,
+    limits::{Cgroup, LimitExceeded, ResourceLimits},
+    mount_backend::MountBackend,
+    sandbox::SandboxProfile,
+    state::{State, TempWorkDir},
+};
+
+/// The time builds see as `SOURCE_DATE_EPOCH`, and the mtime of every file
+/// in a baked output (1980-01-01, the earliest time zip files can store).
+pub const SOURCE_DATE_EPOCH: i64 = 315532800;
+
+const HOSTNAME: &str = "brioche";
+
+/// The number of children currently attached to the terminal (see
+/// `wait_interactive`).
+static INTERACTIVE_CHILDREN: AtomicUsize = AtomicUsize::new(0);
+
+/// Only warn once about falling back to rlimits.
+static CGROUP_WARNING: Once = Once::new();
+
+/// Only warn once about having too many layers for kernel overlayfs.
+static MOUNT_BACKEND_WARNING: Once = Once::new();
+
+/// Check if a child is attached to the terminal, in which case Ctrl-C is
+/// meant for the child rather than for brioche.
+pub fn has_interactive_child() -> bool {
+    INTERACTIVE_CHILDREN.load(Ordering::SeqCst) > 0
+}
 
 pub struct BootstrapEnv {
+    work_dir: PathBuf,
+    /// Keeps GC from removing the work dir while it's in use.
+    _work_dir_lock: TempWorkDir,
+    keep_work_dir: bool,
+    layers_dir: PathBuf,
     inputs_dir: PathBuf,
     outputs_dir: PathBuf,
     source_relative_dir: PathBuf,
     prefix_relative_dir: PathBuf,
     chroot_config: ChrootConfig,
+    resource_limits: ResourceLimits,
+    id_mapping: IdMapping,
+    /// Whether ownership was reset since the last process was spawned, so
+    /// it doesn't need to be reset again when the environment is dropped.
+    ownership_reset: AtomicBool,
+    /// The backend picked when the last process was spawned.
+    mounted_backend: Mutex<Option<MountBackend>>,
+    events: Arc<dyn EventSink>,
 }
 
 impl BootstrapEnv {
     pub async fn new(state: &State) -> anyhow::Result<Self> {
         use target_lexicon::{Aarch64Architecture, Architecture};
 
-        let work_dir = state.new_temp_work_dir().await?;
+        let work_dir_lock = state.new_temp_work_dir().await?;
+        let work_dir = work_dir_lock.path.clone();
 
-        let inputs_dir = work_dir.join("layers").join("inputs");
+        let layers_dir = work_dir.join("layers");
+
+        let inputs_dir = layers_dir.join("inputs");
         fs::create_dir_all(&inputs_dir).await?;
 
-        let overlayfs_work_dir = work_dir.join("layers").join("work-dir");
+        let overlayfs_work_dir = layers_dir.join("work-dir");
         fs::create_dir_all(&overlayfs_work_dir).await?;
 
-        let outputs_dir = work_dir.join("layers").join("outputs");
+        let outputs_dir = layers_dir.join("outputs");
         fs::create_dir_all(&outputs_dir).await?;
 
         let overlay_dir = work_dir.join("overlay");
@@ -58,11 +108,6 @@ impl BootstrapEnv {
             .unpack(&mut alpine_tar_gz, crate::state::UnpackOpts::Reusable)
             .await?;
 
-        println!(
-            "Unzipped Alpine minirootfs to {}",
-            alpine_root_dir.display()
-        );
-
         fs::create_dir_all(inputs_dir.join("etc")).await?;
         fs::copy(
             "/etc/resolv.conf",
@@ -84,20 +129,136 @@ impl BootstrapEnv {
 
         let chroot_config = ChrootConfig {
             lower_dirs: vec![alpine_root_dir, inputs_dir.clone()],
+            layer_mounts: vec![],
+            store_dir: state.recipes_dir.clone(),
+            source_mount: None,
             upper_dir: outputs_dir.clone(),
             work_dir: overlayfs_work_dir,
             target_dir: overlay_dir,
+            mount_backend: crate::mount_backend::detect(state).await?,
+            sandbox_profile: SandboxProfile::default(),
         };
 
         Ok(Self {
+            work_dir,
+            _work_dir_lock: work_dir_lock,
+            keep_work_dir: false,
+            layers_dir,
             inputs_dir,
             outputs_dir,
             source_relative_dir,
             prefix_relative_dir,
             chroot_config,
+            resource_limits: ResourceLimits::default(),
+            id_mapping: IdMapping::detect()?,
+            ownership_reset: AtomicBool::new(true),
+            mounted_backend: Mutex::new(None),
+            events: state.events(),
         })
     }
 
+    /// Keep the work dir around after the environment is dropped (e.g. to
+    /// inspect a failed build), returning its path.
+    pub fn keep_work_dir(&mut self) -> &Path {
+        self.keep_work_dir = true;
+        &self.work_dir
+    }
+
+    /// Add a baked dependency's prefix as a read-only layer, so its files
+    /// show up under the recipe prefix without being copied.
+    pub async fn add_dependency_layer(
+        &mut self,
+        dependency_prefix_path: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        let layer_index = self.chroot_config.layer_mounts.len();
+        let layer_dir = self
+            .layers_dir
+            .join("dependencies")
+            .join(layer_index.to_string());
+        let layer_prefix_dir = layer_dir.join(&self.prefix_relative_dir);
+        fs::create_dir_all(&layer_prefix_dir).await?;
+
+        self.chroot_config.layer_mounts.push(LayerMount {
+            source: dependency_prefix_path.as_ref().to_owned(),
+            target: layer_prefix_dir,
+        });
+
+        // The leftmost lower dir is the topmost layer, so files from later
+        // dependencies take precedence over earlier ones
+        self.chroot_config.lower_dirs.insert(0, layer_dir);
+
+        Ok(())
+    }
+
+    /// The prefixes of the dependency layers, with the one that takes
+    /// precedence first.
+    pub fn dependency_prefixes(&self) -> Vec<&Path> {
+        self.chroot_config
+            .layer_mounts
+            .iter()
+            .rev()
+            .map(|layer_mount| &*layer_mount.source)
+            .collect()
+    }
+
+    /// Mount a host dir read-write as the source dir, so changes made inside
+    /// the environment show up on the host.
+    pub fn mount_source(&mut self, host_dir: impl AsRef<Path>) {
+        let target = self
+            .chroot_config
+            .target_dir
+            .join(&self.source_relative_dir);
+        self.chroot_config.source_mount = Some(LayerMount {
+            source: host_dir.as_ref().to_owned(),
+            target,
+        });
+    }
+
+    /// Set the resource limits for processes spawned in the environment.
+    pub fn set_resource_limits(&mut self, resource_limits: ResourceLimits) {
+        self.resource_limits = resource_limits;
+    }
+
+    /// Set how isolated processes spawned in the environment are from the
+    /// host.
+    pub fn set_sandbox_profile(&mut self, sandbox_profile: SandboxProfile) {
+        self.chroot_config.sandbox_profile = sandbox_profile;
+    }
+
+    /// The backend the environment's layers were mounted with by the last
+    /// spawned process.
+    pub fn mount_backend(&self) -> MountBackend {
+        let mounted_backend = *self.mounted_backend.lock().unwrap();
+        mounted_backend.unwrap_or_else(|| self.select_mount_backend())
+    }
+
+    /// Pick the backend to mount the layers with. Kernel overlayfs can only
+    /// mount so many layers, so environments with more dependencies fall
+    /// back to another backend.
+    fn select_mount_backend(&self) -> MountBackend {
+        match self.chroot_config.mount_backend {
+            MountBackend::KernelOverlayfs
+                if !crate::mount_backend::fits_mount_data(
+                    &self.chroot_config.kernel_overlayfs_options(),
+                ) =>
+            {
+                crate::mount_backend::fallback_backend()
+            }
+            mount_backend => mount_backend,
+        }
+    }
+
+    /// Wait until the files written in the environment go over the disk
+    /// limit. Never finishes if there's no disk limit.
+    pub async fn wait_for_disk_limit(&self) -> anyhow::Result<LimitExceeded> {
+        match self.resource_limits.disk_bytes {
+            Some(limit_bytes) => {
+                crate::limits::wait_for_disk_limit(&self.outputs_dir, limit_bytes).await
+            }
+            None => std::future::pending().await,
+        }
+    }
+
     pub fn bootstrap_target(&self) -> String {
         let mut bootstrap_target = target_lexicon::HOST;
         bootstrap_target.vendor = target_lexicon::Vendor::Custom(
@@ -117,16 +278,34 @@ impl BootstrapEnv {
 
     pub fn recipe_prefix_path(&self) -> RecipePrefix {
         let container_path = PathBuf::from("/").join(&self.prefix_relative_dir);
-        let host_input_path = self.inputs_dir.join(&self.prefix_relative_dir);
         let host_output_path = self.outputs_dir.join(&self.prefix_relative_dir);
 
         RecipePrefix {
             container_path,
-            host_input_path,
             host_output_path,
         }
     }
 
+    /// Make the current user the owner of every file written in the
+    /// environment again (see `IdMapping::reset_ownership`). Builds can only
+    /// write to the outputs dir, so that's the only dir that needs it.
+    pub async fn reset_ownership(&self) -> anyhow::Result<()> {
+        let id_mapping = self.id_mapping.clone();
+        let outputs_dir = self.outputs_dir.clone();
+        tokio::task::spawn_blocking(move || id_mapping.reset_ownership(&outputs_dir)).await??;
+        self.ownership_reset.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn warn(&self, message: String) {
+        self.events.emit(Event::Message {
+            level: MessageLevel::Warning,
+            message,
+            details: vec![],
+        });
+    }
+
     pub fn spawn(&self, command: &Command) -> anyhow::Result<Child> {
         let recipe_prefix = self.recipe_prefix_path();
 
@@ -141,6 +320,9 @@ impl BootstrapEnv {
             ),
         );
         spawn_cmd.env("HOME", "/root");
+        spawn_cmd.env("SOURCE_DATE_EPOCH", SOURCE_DATE_EPOCH.to_string());
+        spawn_cmd.env("TZ", "UTC");
+        spawn_cmd.env("LANG", "C.UTF-8");
         spawn_cmd.chroot_dir(&self.chroot_config.target_dir);
 
         spawn_cmd.args(&command.args);
@@ -154,49 +336,179 @@ impl BootstrapEnv {
             &unshare::Namespace::Mount,
             &unshare::Namespace::Pid,
             &unshare::Namespace::User,
+            &unshare::Namespace::Uts,
         ]);
-        spawn_cmd.stdin(unshare::Stdio::Pipe);
-        spawn_cmd.stdout(unshare::Stdio::Pipe);
-        spawn_cmd.stderr(unshare::Stdio::Pipe);
-
-        let current_uid = nix::unistd::Uid::current().as_raw();
-        let current_gid = nix::unistd::Gid::current().as_raw();
-        let newuidmap = which::which("newuidmap")?;
-        let newgidmap = which::which("newgidmap")?;
-        spawn_cmd.set_id_map_commands(&newuidmap, &newgidmap);
-        spawn_cmd.set_id_maps(
-            vec![unshare::UidMap {
-                outside_uid: current_uid,
-                inside_uid: 0,
-                count: 1,
-            }],
-            vec![unshare::GidMap {
-                outside_gid: current_gid,
-                inside_gid: 0,
-                count: 1,
-            }],
-        );
+        if command.inherit_stdio {
+            spawn_cmd.stdin(unshare::Stdio::Inherit);
+            spawn_cmd.stdout(unshare::Stdio::Inherit);
+            spawn_cmd.stderr(unshare::Stdio::Inherit);
+        } else {
+            spawn_cmd.stdin(unshare::Stdio::Pipe);
+            spawn_cmd.stdout(unshare::Stdio::Pipe);
+            spawn_cmd.stderr(unshare::Stdio::Pipe);
+        }
+
+        self.id_mapping.set_id_maps(&mut spawn_cmd);
         spawn_cmd.uid(0);
         spawn_cmd.gid(0);
 
-        let chroot_config = self.chroot_config.clone();
+        let resource_limits = self.resource_limits.clone();
+        let cgroup = if resource_limits.needs_cgroup() {
+            match crate::limits::Cgroup::create(&resource_limits, self.events.clone()) {
+                Ok(cgroup) => Some(cgroup),
+                Err(error) if resource_limits.max_processes.is_some() => {
+                    return Err(error.context(
+                        "can't create a cgroup for builds, which is needed for --max-processes",
+                    ));
+                }
+                Err(error) => {
+                    CGROUP_WARNING.call_once(|| {
+                        self.events.emit(Event::Message {
+                            level: MessageLevel::Warning,
+                            message: format!(
+                                "can't create a cgroup for builds ({:#}), falling back to rlimits",
+                                error
+                            ),
+                            details: vec![],
+                        });
+                    });
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let id_mapping = self.id_mapping.clone();
+        let cgroup_procs_path = cgroup.as_ref().map(|cgroup| cgroup.procs_path());
+        spawn_cmd.before_unfreeze(move |pid| {
+            if !id_mapping.uses_helpers() {
+                id_mapping.write_maps(pid)?;
+            }
+
+            // Move the child into its cgroup before it starts running, so
+            // every process it starts is in the cgroup too
+            if let Some(procs_path) = &cgroup_procs_path {
+                std::fs::write(procs_path, pid.to_string())?;
+            }
+
+            Ok(())
+        });
+        let use_rlimits = cgroup.is_none();
+
+        let sandbox_profile = self.chroot_config.sandbox_profile;
+        let mut chroot_config = self.chroot_config.clone();
+        let mount_backend = self.select_mount_backend();
+        if mount_backend != chroot_config.mount_backend {
+            MOUNT_BACKEND_WARNING.call_once(|| {
+                self.warn(format!(
+                    "too many layers to mount with kernel overlayfs, using {} instead",
+                    mount_backend
+                ));
+            });
+            chroot_config.mount_backend = mount_backend;
+        }
+        if mount_backend == MountBackend::Copy {
+            chroot_config.copy_layers()?;
+        }
+        *self.mounted_backend.lock().unwrap() = Some(mount_backend);
+
         spawn_cmd.before_chroot(move || {
+            // Don't let the host's hostname or umask leak into the build
+            nix::unistd::sethostname(HOSTNAME)?;
+            nix::sys::stat::umask(nix::sys::stat::Mode::from_bits_truncate(0o022));
+
             let chroot_config = chroot_config.clone();
             let mount_result = chroot_config.mount();
             match mount_result {
-                Ok(()) => Ok(()),
+                Ok(()) => {}
                 Err(error) => {
-                    eprintln!("failed to set up system mounts: {}", error);
-                    Err(std::io::Error::new(std::io::ErrorKind::Other, error))
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        error.context("failed to set up system mounts"),
+                    ));
                 }
             }
+
+            // Applied after mounting, so fuse-overlayfs isn't limited
+            if use_rlimits {
+                crate::limits::apply_memory_rlimit(&resource_limits)?;
+            }
+            crate::limits::apply_file_size_rlimit(&resource_limits)?;
+            if let Some(cpus) = resource_limits.cpus {
+                crate::limits::restrict_cpus(cpus)?;
+            }
+
+            // Applied last, since setting up the sandbox needs the
+            // capabilities and syscalls being taken away. The filter also
+            // applies to the chroot and exec that follow, which are allowed
+            if sandbox_profile == SandboxProfile::Hardened {
+                crate::sandbox::drop_capabilities()?;
+                crate::sandbox::install_seccomp_filter()?;
+            }
+
+            Ok(())
         });
 
+        self.ownership_reset.store(false, Ordering::SeqCst);
         let child = spawn_cmd
             .spawn()
             .map_err(|error| anyhow::anyhow!("failed to spawn child process: {}", error))?;
 
-        Ok(Child { child })
+        Ok(Child {
+            child,
+            cgroup,
+            rlimits: if use_rlimits {
+                Some(self.resource_limits.clone())
+            } else {
+                None
+            },
+            events: self.events.clone(),
+        })
+    }
+}
+
+impl Drop for BootstrapEnv {
+    fn drop(&mut self) {
+        if !self.ownership_reset.load(Ordering::SeqCst) {
+            if let Err(error) = self.id_mapping.reset_ownership(&self.outputs_dir) {
+                self.warn(format!(
+                    "failed to reset ownership of work dir {}: {:#}",
+                    self.work_dir.display(),
+                    error
+                ));
+            }
+        }
+
+        if self.keep_work_dir {
+            return;
+        }
+
+        // The overlay is normally mounted in the child's mount namespace and
+        // goes away with it, but make sure it isn't still mounted here
+        // before removing the work dir out from under it
+        if let Err(error) = self.chroot_config.unmount() {
+            self.warn(format!(
+                "failed to unmount overlay at {}: {:#}",
+                self.chroot_config.target_dir.display(),
+                error
+            ));
+            return;
+        }
+
+        if let Err(error) = std::fs::remove_dir_all(&self.work_dir) {
+            self.warn(format!(
+                "failed to remove work dir {}: {}",
+                self.work_dir.display(),
+                error
+            ));
+            return;
+        }
+
+        // Also remove the parent dir created by `State::new_temp_work_dir`
+        if let Some(parent_dir) = self.work_dir.parent() {
+            let _ = std::fs::remove_dir(parent_dir);
+        }
     }
 }
 
@@ -205,6 +517,7 @@ pub struct Command {
     args: Vec<OsString>,
     env: HashMap<OsString, OsString>,
     current_dir: Option<PathBuf>,
+    inherit_stdio: bool,
 }
 
 impl Command {
@@ -214,13 +527,14 @@ impl Command {
             args: vec![],
             env: HashMap::new(),
             current_dir: None,
+            inherit_stdio: false,
         }
     }
 
-    // pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
-    //     self.args.push(arg.as_ref().to_owned());
-    //     self
-    // }
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
 
     pub fn env(&mut self, var: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> &mut Self {
         self.env
@@ -232,10 +546,20 @@ impl Command {
         self.current_dir = Some(current_dir.as_ref().to_owned());
         self
     }
+
+    /// Attach the command to this process's stdin, stdout, and stderr
+    /// instead of piping them.
+    pub fn inherit_stdio(&mut self) -> &mut Self {
+        self.inherit_stdio = true;
+        self
+    }
 }
 
 pub struct Child {
     child: unshare::Child,
+    cgroup: Option<Cgroup>,
+    rlimits: Option<ResourceLimits>,
+    events: Arc<dyn EventSink>,
 }
 
 impl Child {
@@ -248,17 +572,197 @@ impl Child {
     }
 
     pub fn take_stderr(&mut self) -> Option<impl std::io::Read> {
-        self.child.stdout.take()
+        self.child.stderr.take()
+    }
+
+    /// Take the cgroup the child runs in (if limits are enforced with a
+    /// cgroup). The cgroup is removed once it's dropped.
+    pub fn take_cgroup(&mut self) -> Option<Cgroup> {
+        self.cgroup.take()
+    }
+
+    /// Take the limits the child runs with if they're enforced with rlimits
+    /// instead of a cgroup.
+    pub fn take_rlimits(&mut self) -> Option<ResourceLimits> {
+        self.rlimits.take()
     }
 
     pub fn wait(&mut self) -> anyhow::Result<unshare::ExitStatus> {
         let exit_status = self.child.wait()?;
         Ok(exit_status)
     }
+
+    /// Get a guard that kills the child when dropped, unless it gets
+    /// disarmed after the child exits.
+    pub fn kill_guard(&self) -> KillGuard {
+        KillGuard {
+            pid: Some(nix::unistd::Pid::from_raw(self.child.pid())),
+            events: self.events.clone(),
+        }
+    }
+
+    /// Wait for the child to exit, also returning the resources it used
+    /// (including the resources used by any descendants it waited for).
+    pub fn wait_with_usage(mut self) -> anyhow::Result<(unshare::ExitStatus, ResourceUsage)> {
+        use nix::libc;
+
+        // Wait for the child to exit without reaping it, which is the only
+        // way to get the resources it used while still letting `unshare`
+        // reap it below. `waitid` can only return resource usage as a raw
+        // syscall
+        let pid = self.child.pid();
+        // SAFETY: Both are plain C structs, so all zeroes is valid
+        let mut siginfo: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+        loop {
+            // SAFETY: `waitid` only writes to `siginfo` and `rusage`
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_waitid,
+                    libc::P_PID,
+                    pid,
+                    &mut siginfo as *mut libc::siginfo_t,
+                    libc::WEXITED | libc::WNOWAIT,
+                    &mut rusage as *mut libc::rusage,
+                )
+            };
+            if result >= 0 {
+                break;
+            }
+
+            let error = std::io::Error::last_os_error();
+            if error.kind() != std::io::ErrorKind::Interrupted {
+                return Err(error.into());
+            }
+        }
+
+        let exit_status = self.child.wait()?;
+
+        let usage = ResourceUsage {
+            cpu_time: timeval_duration(rusage.ru_utime) + timeval_duration(rusage.ru_stime),
+            // `ru_maxrss` is measured in kilobytes
+            max_process_rss_bytes: rusage.ru_maxrss as u64 * 1024,
+        };
+
+        Ok((exit_status, usage))
+    }
+
+    /// Wait for a child attached to the current terminal to exit (see
+    /// `wait_interactive`).
+    pub async fn wait_interactive(mut self) -> anyhow::Result<unshare::ExitStatus> {
+        let mut kill_guard = self.kill_guard();
+        let child_task = tokio::task::spawn_blocking(move || self.wait());
+        let exit_status = wait_interactive(child_task).await;
+        kill_guard.disarm();
+
+        exit_status?
+    }
+}
+
+/// Wait for a child attached to the current terminal, which counts as an
+/// interactive child until it exits (or the wait gets cancelled). Ctrl-C is
+/// delivered to the child through the terminal, so keep waiting instead of
+/// exiting.
+pub async fn wait_interactive<T>(child: impl std::future::Future<Output = T>) -> T {
+    INTERACTIVE_CHILDREN.fetch_add(1, Ordering::SeqCst);
+    let _interactive_guard = InteractiveGuard;
+
+    tokio::pin!(child);
+    loop {
+        tokio::select! {
+            result = &mut child => {
+                return result;
+            }
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+}
+
+struct InteractiveGuard;
+
+impl Drop for InteractiveGuard {
+    fn drop(&mut self) {
+        INTERACTIVE_CHILDREN.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Get the exit code a shell would report for a sandboxed child: its own
+/// exit code, or 128 plus the signal that killed it.
+pub fn exit_code(exit_status: &unshare::ExitStatus) -> i32 {
+    match exit_status {
+        unshare::ExitStatus::Exited(exit_code) => *exit_code as u8 as i32,
+        unshare::ExitStatus::Signaled(signal, _) => 128 + *signal as i32,
+    }
+}
+
+/// Get the exit code a shell would report for a child on the host (see
+/// `exit_code`).
+pub fn host_exit_code(exit_status: std::process::ExitStatus) -> anyhow::Result<i32> {
+    use std::os::unix::process::ExitStatusExt as _;
+
+    match (exit_status.code(), exit_status.signal()) {
+        (Some(exit_code), _) => Ok(exit_code),
+        (None, Some(signal)) => Ok(128 + signal),
+        (None, None) => anyhow::bail!("process exited with status {}", exit_status),
+    }
+}
+
+/// Kills a sandboxed child, along with everything else running in the
+/// sandbox. The child is the init process of its own PID namespace, so the
+/// kernel kills every other process in the namespace (including
+/// fuse-overlayfs, which takes the overlay mount with it) once it's gone.
+pub struct KillGuard {
+    pid: Option<nix::unistd::Pid>,
+    events: Arc<dyn EventSink>,
+}
+
+impl KillGuard {
+    pub fn kill(&mut self) -> anyhow::Result<()> {
+        if let Some(pid) = self.pid.take() {
+            match nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL) {
+                Ok(()) | Err(nix::errno::Errno::ESRCH) => {}
+                Err(error) => {
+                    return Err(error.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stop the guard from killing the child, once the child has exited
+    /// (its PID could get reused afterwards).
+    pub fn disarm(&mut self) {
+        self.pid = None;
+    }
+}
+
+impl Drop for KillGuard {
+    fn drop(&mut self) {
+        if let Err(error) = self.kill() {
+            self.events.emit(Event::Message {
+                level: MessageLevel::Warning,
+                message: format!("failed to kill sandboxed process: {:#}", error),
+                details: vec![],
+            });
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceUsage {
+    /// User and system CPU time.
+    pub cpu_time: std::time::Duration,
+    /// Peak resident memory of the largest single process.
+    pub max_process_rss_bytes: u64,
+}
+
+fn timeval_duration(timeval: nix::libc::timeval) -> std::time::Duration {
+    std::time::Duration::from_secs(timeval.tv_sec as u64)
+        + std::time::Duration::from_micros(timeval.tv_usec as u64)
 }
 
 pub struct RecipePrefix {
-    pub host_input_path: PathBuf,
     pub host_output_path: PathBuf,
     pub container_path: PathBuf,
 }
@@ -266,13 +770,99 @@ pub struct RecipePrefix {
 #[derive(Debug, Clone)]
 struct ChrootConfig {
     lower_dirs: Vec<PathBuf>,
+    layer_mounts: Vec<LayerMount>,
+    /// Baked outputs get relocated to their path in the store, so the store
+    /// is mounted read-only at the same path within the container for
+    /// dependencies to keep working.
+    store_dir: PathBuf,
+    /// A host dir to mount read-write over the source dir, instead of
+    /// copying the source into the inputs layer.
+    source_mount: Option<LayerMount>,
     upper_dir: PathBuf,
     work_dir: PathBuf,
     target_dir: PathBuf,
+    mount_backend: MountBackend,
+    sandbox_profile: SandboxProfile,
+}
+
+/// A bind mount into one of the lower dirs (set up before the overlay is
+/// mounted) or into the overlay itself. Because the bind mount is made in
+/// the child's mount namespace, it's never visible on the host.
+#[derive(Debug, Clone)]
+struct LayerMount {
+    source: PathBuf,
+    target: PathBuf,
 }
 
 impl ChrootConfig {
     fn mount(self) -> anyhow::Result<()> {
+        for layer_mount in &self.layer_mounts {
+            libmount::BindMount::new(&layer_mount.source, &layer_mount.target)
+                .mount()
+                .map_err(|error| anyhow::anyhow!("{}", error))?;
+        }
+
+        match self.mount_backend {
+            MountBackend::KernelOverlayfs => {
+                self.mount_kernel_overlayfs()
+                    .map_err(|error| error.context("mounting kernel overlayfs failed"))?;
+            }
+            MountBackend::FuseOverlayfs => {
+                self.mount_fuse_overlayfs()?;
+            }
+            MountBackend::Copy => {
+                self.mount_copied_root()?;
+            }
+        }
+
+        let store_mount_dir = self.target_dir.join(self.store_dir.strip_prefix("/")?);
+        std::fs::create_dir_all(&store_mount_dir)?;
+        nix::mount::mount(
+            Some(&self.store_dir),
+            &store_mount_dir,
+            None::<&str>,
+            nix::mount::MsFlags::MS_BIND,
+            None::<&str>,
+        )?;
+        crate::sandbox::remount_readonly(&store_mount_dir, nix::mount::MsFlags::empty())?;
+
+        if let Some(source_mount) = &self.source_mount {
+            libmount::BindMount::new(&source_mount.source, &source_mount.target)
+                .mount()
+                .map_err(|error| anyhow::anyhow!("{}", error))?;
+        }
+
+        match self.sandbox_profile {
+            SandboxProfile::Hardened => {
+                crate::sandbox::mount_hardened_system_dirs(&self.target_dir)?;
+            }
+            SandboxProfile::Permissive => {
+                self.mount_host_system_dirs()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn kernel_overlayfs_options(&self) -> String {
+        let lower_dirs: Vec<_> = self.lower_dirs.iter().map(|dir| dir.as_path()).collect();
+        crate::mount_backend::overlayfs_mount_options(&lower_dirs, &self.upper_dir, &self.work_dir)
+    }
+
+    fn mount_kernel_overlayfs(&self) -> anyhow::Result<()> {
+        let mount_options = self.kernel_overlayfs_options();
+        nix::mount::mount(
+            Some("overlay"),
+            &self.target_dir,
+            Some("overlay"),
+            nix::mount::MsFlags::empty(),
+            Some(&*mount_options),
+        )?;
+
+        Ok(())
+    }
+
+    fn mount_fuse_overlayfs(&self) -> anyhow::Result<()> {
         let lower_dirs = self
             .lower_dirs
             .iter()
@@ -298,6 +888,51 @@ impl ChrootConfig {
             );
         }
 
+        Ok(())
+    }
+
+    /// Copy every lower dir into the upper dir for the copy backend, before
+    /// spawning the child. The layer mounts don't exist outside the child,
+    /// so the dirs they mount are copied into place directly. Files copied
+    /// from dependencies end up in the output prefix, but they get removed
+    /// along with any other unchanged dependency files (see
+    /// `output::exclude_dependency_files`).
+    fn copy_layers(&self) -> anyhow::Result<()> {
+        // The overlayfs work dir isn't used otherwise, so it marks whether
+        // the layers were already copied by an earlier process (e.g. before
+        // starting a debug shell), in which case copying again would
+        // overwrite the changes from that process
+        let copied_marker_path = self.work_dir.join("copied");
+        if copied_marker_path.exists() {
+            return Ok(());
+        }
+
+        // The topmost layer is first, so copy from the bottom up
+        for lower_dir in self.lower_dirs.iter().rev() {
+            copy_dir_contents(lower_dir, &self.upper_dir)?;
+
+            for layer_mount in &self.layer_mounts {
+                if let Ok(relative_target) = layer_mount.target.strip_prefix(lower_dir) {
+                    copy_dir_contents(&layer_mount.source, &self.upper_dir.join(relative_target))?;
+                }
+            }
+        }
+        std::fs::write(&copied_marker_path, "")?;
+
+        Ok(())
+    }
+
+    /// Use the upper dir as the root, after `copy_layers` copied every layer
+    /// into it.
+    fn mount_copied_root(&self) -> anyhow::Result<()> {
+        libmount::BindMount::new(&self.upper_dir, &self.target_dir)
+            .mount()
+            .map_err(|error| anyhow::anyhow!("{}", error))?;
+
+        Ok(())
+    }
+
+    fn mount_host_system_dirs(&self) -> anyhow::Result<()> {
         libmount::BindMount::new("/proc", self.target_dir.join("proc"))
             .mount()
             .map_err(|error| anyhow::anyhow!("{}", error))?;
@@ -310,4 +945,61 @@ impl ChrootConfig {
 
         Ok(())
     }
+
+    fn unmount(&self) -> anyhow::Result<()> {
+        use std::os::unix::fs::MetadataExt as _;
+
+        let target_metadata = match std::fs::metadata(&self.target_dir) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(());
+            }
+            Err(error) => {
+                return Err(error.into());
+            }
+        };
+        let parent_metadata = match self.target_dir.parent() {
+            Some(parent_dir) => std::fs::metadata(parent_dir)?,
+            None => {
+                return Ok(());
+            }
+        };
+
+        // The target dir is only a mount point if it's on a different device
+        // than its parent
+        if target_metadata.dev() == parent_metadata.dev() {
+            return Ok(());
+        }
+
+        let fusermount_status = std::process::Command::new("fusermount")
+            .arg("-u")
+            .arg(&self.target_dir)
+            .status()?;
+        if !fusermount_status.success() {
+            anyhow::bail!(
+                "unmounting overlayfs failed with exit code {}",
+                fusermount_status
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn copy_dir_contents(source_dir: &Path, target_dir: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(target_dir)?;
+    let cp_status = std::process::Command::new("cp")
+        .arg("-a")
+        .arg(source_dir.join("."))
+        .arg(target_dir)
+        .status()?;
+    if !cp_status.success() {
+        anyhow::bail!(
+            "copying layer {} failed with exit code {}",
+            source_dir.display(),
+            cp_status
+        );
+    }
+
+    Ok(())
 }
@@ -0,0 +1,347 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use joinery::JoinableIterator as _;
+
+use crate::{
+    bake::BakeOptions,
+    event::{Event, MessageLevel},
+    hash::Hash,
+    recipe::{ResolvedRecipe, ResolvedRecipeRef, ResolvedRecipeSet},
+    state::State,
+};
+
+/// The maximum number of differing paths to list.
+const MAX_REPORTED_DIFFERENCES: usize = 50;
+
+/// Bake a recipe that's already in the store again in a fresh bootstrap env,
+/// then compare the new output with the stored output to see if the recipe
+/// is reproducible.
+pub async fn check(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    options: &BakeOptions,
+) -> anyhow::Result<()> {
+    let recipe = recipe_set.get(recipe_ref);
+    let stored_prefix_path = match state.get_recipe_output(recipe_ref)? {
+        Some(prefix_path) => prefix_path,
+        None => {
+            anyhow::bail!(
+                "recipe {} {} has not been baked, build it before checking it",
+                recipe.name,
+                recipe.version
+            );
+        }
+    };
+
+    let mut bootstrap_env = crate::bootstrap_env::BootstrapEnv::new(state).await?;
+    state.emit(Event::Message {
+        level: MessageLevel::Info,
+        message: format!("Rebaking recipe {} {}", recipe.name, recipe.version),
+        details: vec![],
+    });
+    let rebake_result =
+        crate::bake::rebake_in_env(state, recipe_set, recipe_ref, options, &mut bootstrap_env)
+            .await;
+    let rebaked_prefix_path = match rebake_result {
+        Ok(rebaked_prefix_path) => rebaked_prefix_path,
+        Err(error) => {
+            if options.keep_failed {
+                keep_work_dir(state, recipe, &mut bootstrap_env);
+            }
+            return Err(error);
+        }
+    };
+
+    let stored_tree = read_tree(&stored_prefix_path).await?;
+    let rebaked_tree = read_tree(&rebaked_prefix_path).await?;
+    let differences = diff_trees(
+        &stored_prefix_path,
+        &stored_tree,
+        &rebaked_prefix_path,
+        &rebaked_tree,
+    )
+    .await?;
+
+    if differences.is_empty() {
+        state.emit(Event::Message {
+            level: MessageLevel::Info,
+            message: format!(
+                "Recipe {} {} is reproducible ({} paths match)",
+                recipe.name,
+                recipe.version,
+                stored_tree.len()
+            ),
+            details: vec![],
+        });
+        return Ok(());
+    }
+
+    let mut details: Vec<_> = differences
+        .iter()
+        .take(MAX_REPORTED_DIFFERENCES)
+        .map(|(path, difference)| format!("{}: {}", path.display(), difference))
+        .collect();
+    if differences.len() > MAX_REPORTED_DIFFERENCES {
+        details.push(format!(
+            "...and {} more",
+            differences.len() - MAX_REPORTED_DIFFERENCES
+        ));
+    }
+    state.emit(Event::Message {
+        level: MessageLevel::Warning,
+        message: format!(
+            "{} paths differ between the stored and rebaked outputs of {} {}:",
+            differences.len(),
+            recipe.name,
+            recipe.version
+        ),
+        details,
+    });
+
+    if options.keep_failed {
+        keep_work_dir(state, recipe, &mut bootstrap_env);
+    }
+
+    anyhow::bail!(
+        "recipe {} {} is not reproducible",
+        recipe.name,
+        recipe.version
+    );
+}
+
+/// Keep the work dir of a rebake that failed or wasn't reproducible, so
+/// the rebaked output can be inspected.
+fn keep_work_dir(
+    state: &State,
+    recipe: &ResolvedRecipe,
+    bootstrap_env: &mut crate::bootstrap_env::BootstrapEnv,
+) {
+    let work_dir = bootstrap_env.keep_work_dir();
+    state.emit(Event::Message {
+        level: MessageLevel::Info,
+        message: format!(
+            "Kept work dir for rebake of {} {}: {}",
+            recipe.name,
+            recipe.version,
+            work_dir.display()
+        ),
+        details: vec![],
+    });
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TreeEntry {
+    mode: u32,
+    kind: TreeEntryKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TreeEntryKind {
+    File { size: u64, hash: Hash },
+    Symlink { target: PathBuf },
+    Directory,
+    Other,
+}
+
+impl TreeEntryKind {
+    fn describe(&self) -> &'static str {
+        match self {
+            TreeEntryKind::File { .. } => "file",
+            TreeEntryKind::Symlink { .. } => "symlink",
+            TreeEntryKind::Directory => "directory",
+            TreeEntryKind::Other => "special file",
+        }
+    }
+}
+
+/// List every path within a prefix (relative to the prefix), along with the
+/// details that get compared between outputs.
+async fn read_tree(prefix_path: &Path) -> anyhow::Result<BTreeMap<PathBuf, TreeEntry>> {
+    let prefix_path = prefix_path.to_owned();
+    let tree = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        use sha2::Digest as _;
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let mut tree = BTreeMap::new();
+        let mut pending_dirs = vec![PathBuf::new()];
+        while let Some(relative_dir) = pending_dirs.pop() {
+            for entry in std::fs::read_dir(prefix_path.join(&relative_dir))? {
+                let entry = entry?;
+                let entry_path = entry.path();
+                let relative_path = relative_dir.join(entry.file_name());
+                let metadata = std::fs::symlink_metadata(&entry_path)?;
+                let file_type = metadata.file_type();
+
+                let kind = if file_type.is_symlink() {
+                    TreeEntryKind::Symlink {
+                        target: std::fs::read_link(&entry_path)?,
+                    }
+                } else if file_type.is_file() {
+                    let mut file_hash = sha2::Sha256::new();
+                    let mut file = std::fs::File::open(&entry_path)?;
+                    std::io::copy(&mut file, &mut file_hash)?;
+
+                    TreeEntryKind::File {
+                        size: metadata.len(),
+                        hash: Hash::from_digest(file_hash),
+                    }
+                } else if file_type.is_dir() {
+                    pending_dirs.push(relative_path.clone());
+                    TreeEntryKind::Directory
+                } else {
+                    TreeEntryKind::Other
+                };
+
+                let mode = metadata.permissions().mode() & 0o7777;
+                tree.insert(relative_path, TreeEntry { mode, kind });
+            }
+        }
+
+        Ok(tree)
+    })
+    .await??;
+
+    Ok(tree)
+}
+
+/// Compare two trees, returning a short summary for each path that differs.
+async fn diff_trees(
+    a_prefix_path: &Path,
+    a_tree: &BTreeMap<PathBuf, TreeEntry>,
+    b_prefix_path: &Path,
+    b_tree: &BTreeMap<PathBuf, TreeEntry>,
+) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let mut differences = vec![];
+
+    for (path, a_entry) in a_tree {
+        let b_entry = match b_tree.get(path) {
+            Some(b_entry) => b_entry,
+            None => {
+                differences.push((path.clone(), "missing from rebaked output".to_string()));
+                continue;
+            }
+        };
+
+        if a_entry == b_entry {
+            continue;
+        }
+
+        let mut changes = vec![];
+        if a_entry.mode != b_entry.mode {
+            changes.push(format!("mode {:o} -> {:o}", a_entry.mode, b_entry.mode));
+        }
+
+        match (&a_entry.kind, &b_entry.kind) {
+            (
+                TreeEntryKind::File {
+                    size: a_size,
+                    hash: a_hash,
+                },
+                TreeEntryKind::File {
+                    size: b_size,
+                    hash: b_hash,
+                },
+            ) => {
+                if a_size != b_size {
+                    changes.push(format!("size {} -> {}", a_size, b_size));
+                }
+                if a_hash != b_hash {
+                    changes.push(format!("content hash {} -> {}", a_hash, b_hash));
+
+                    let offset = first_difference_offset(
+                        &a_prefix_path.join(path),
+                        &b_prefix_path.join(path),
+                    )
+                    .await?;
+                    if let Some(offset) = offset {
+                        changes.push(format!("first difference at byte {}", offset));
+                    }
+                }
+            }
+            (
+                TreeEntryKind::Symlink { target: a_target },
+                TreeEntryKind::Symlink { target: b_target },
+            ) if a_target != b_target => {
+                changes.push(format!(
+                    "symlink target {} -> {}",
+                    a_target.display(),
+                    b_target.display()
+                ));
+            }
+            (a_kind, b_kind) if a_kind.describe() != b_kind.describe() => {
+                changes.push(format!("{} -> {}", a_kind.describe(), b_kind.describe()));
+            }
+            _ => {}
+        }
+
+        if !changes.is_empty() {
+            differences.push((
+                path.clone(),
+                changes.into_iter().join_with(", ").to_string(),
+            ));
+        }
+    }
+
+    for path in b_tree.keys() {
+        if !a_tree.contains_key(path) {
+            differences.push((path.clone(), "only in rebaked output".to_string()));
+        }
+    }
+
+    differences.sort_by(|(a_path, _), (b_path, _)| a_path.cmp(b_path));
+    Ok(differences)
+}
+
+/// Find the offset of the first byte that differs between two files. Returns
+/// `None` if one file is a prefix of the other.
+async fn first_difference_offset(a_path: &Path, b_path: &Path) -> anyhow::Result<Option<u64>> {
+    let a_path = a_path.to_owned();
+    let b_path = b_path.to_owned();
+    let offset = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let mut a_file = std::io::BufReader::new(std::fs::File::open(&a_path)?);
+        let mut b_file = std::io::BufReader::new(std::fs::File::open(&b_path)?);
+
+        let mut offset = 0;
+        let mut a_buf = [0; 8192];
+        let mut b_buf = [0; 8192];
+        loop {
+            let a_len = read_full(&mut a_file, &mut a_buf)?;
+            let b_len = read_full(&mut b_file, &mut b_buf)?;
+            let len = a_len.min(b_len);
+
+            let position = a_buf[..len]
+                .iter()
+                .zip(&b_buf[..len])
+                .position(|(a, b)| a != b);
+            if let Some(position) = position {
+                return Ok(Some(offset + position as u64));
+            }
+
+            if a_len != b_len || len == 0 {
+                return Ok(None);
+            }
+
+            offset += len as u64;
+        }
+    })
+    .await??;
+
+    Ok(offset)
+}
+
+/// Read until the buffer is full or the end of the file is reached.
+fn read_full(reader: &mut impl std::io::Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+
+    Ok(len)
+}
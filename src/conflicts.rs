@@ -0,0 +1,154 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use joinery::JoinableIterator as _;
+
+use crate::{
+    bake::BakedRecipe,
+    event::{Event, MessageLevel},
+    recipe::{ConflictPolicy, ResolvedRecipe, ResolvedRecipeSet},
+    state::State,
+};
+
+/// The maximum number of conflicting paths to list in an error.
+const MAX_REPORTED_CONFLICTS: usize = 20;
+
+/// Check the prefixes of a recipe's dependencies for paths provided by more
+/// than one dependency, resolving them using the recipe's conflict policy.
+/// Returns the dependencies in the order they should be layered, with the
+/// dependency that should take precedence last.
+pub async fn order_dependency_layers<'a>(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe: &ResolvedRecipe,
+    dependency_recipes: &'a [BakedRecipe],
+) -> anyhow::Result<Vec<&'a BakedRecipe>> {
+    // Sort dependencies from highest to lowest precedence
+    let mut dependencies: Vec<_> = dependency_recipes.iter().collect();
+    dependencies.sort_by_key(|dependency| {
+        let dependency_recipe = recipe_set.get(&dependency.recipe_ref);
+        let priority = match &recipe.conflict_policy {
+            ConflictPolicy::LastWins | ConflictPolicy::Error | ConflictPolicy::FirstWins => None,
+            ConflictPolicy::Priority(priority) => priority
+                .iter()
+                .position(|name| *name == dependency_recipe.name),
+        };
+
+        // Dependencies in the priority list come first
+        (
+            priority.is_none(),
+            priority,
+            &dependency_recipe.name,
+            &dependency_recipe.version,
+            dependency.recipe_ref,
+        )
+    });
+    // With last-wins, the dependency whose name sorts last takes precedence
+    if recipe.conflict_policy == ConflictPolicy::LastWins {
+        dependencies.reverse();
+    }
+
+    let mut paths = BTreeMap::<PathBuf, Vec<(usize, bool)>>::new();
+    for (index, dependency) in dependencies.iter().enumerate() {
+        for (path, is_dir) in list_prefix_paths(&dependency.prefix_path).await? {
+            paths.entry(path).or_default().push((index, is_dir));
+        }
+    }
+
+    let mut num_resolved = 0;
+    let mut unresolved = vec![];
+    for (path, providers) in &paths {
+        let is_conflict = providers.len() > 1 && providers.iter().any(|(_, is_dir)| !is_dir);
+        if !is_conflict {
+            continue;
+        }
+
+        // Providers are in precedence order, so the first one wins
+        let (winner_index, _) = providers[0];
+        let winner_name = &recipe_set.get(&dependencies[winner_index].recipe_ref).name;
+        let is_resolved = match &recipe.conflict_policy {
+            ConflictPolicy::Error => false,
+            ConflictPolicy::LastWins | ConflictPolicy::FirstWins => true,
+            ConflictPolicy::Priority(priority) => priority.contains(winner_name),
+        };
+
+        if is_resolved {
+            num_resolved += 1;
+        } else {
+            let provider_names = providers
+                .iter()
+                .map(|(index, _)| {
+                    let provider = recipe_set.get(&dependencies[*index].recipe_ref);
+                    format!("{} {}", provider.name, provider.version)
+                })
+                .join_with(", ");
+            unresolved.push(format!("{} ({})", path.display(), provider_names));
+        }
+    }
+
+    if !unresolved.is_empty() {
+        let mut message = format!(
+            "{} paths conflict between dependencies of {} {}:",
+            unresolved.len(),
+            recipe.name,
+            recipe.version
+        );
+        for conflict in unresolved.iter().take(MAX_REPORTED_CONFLICTS) {
+            message.push_str("\n  ");
+            message.push_str(conflict);
+        }
+        if unresolved.len() > MAX_REPORTED_CONFLICTS {
+            message.push_str(&format!(
+                "\n  ...and {} more",
+                unresolved.len() - MAX_REPORTED_CONFLICTS
+            ));
+        }
+        message.push_str("\nset a `conflicts` policy in the recipe to resolve them");
+
+        anyhow::bail!(message);
+    }
+
+    if num_resolved > 0 {
+        state.emit(Event::Message {
+            level: MessageLevel::Info,
+            message: format!(
+                "Resolved {} conflicting paths between dependencies of {} {}",
+                num_resolved, recipe.name, recipe.version
+            ),
+            details: vec![],
+        });
+    }
+
+    // Layers added later take precedence, so reverse the order
+    dependencies.reverse();
+    Ok(dependencies)
+}
+
+/// List every path within a prefix (relative to the prefix), along with
+/// whether the path is a directory.
+async fn list_prefix_paths(prefix_path: &Path) -> anyhow::Result<Vec<(PathBuf, bool)>> {
+    let prefix_path = prefix_path.to_owned();
+    let paths = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let mut paths = vec![];
+        let mut pending_dirs = vec![PathBuf::new()];
+        while let Some(relative_dir) = pending_dirs.pop() {
+            for entry in std::fs::read_dir(prefix_path.join(&relative_dir))? {
+                let entry = entry?;
+                let relative_path = relative_dir.join(entry.file_name());
+                let is_dir = entry.file_type()?.is_dir();
+                if is_dir {
+                    pending_dirs.push(relative_path.clone());
+                }
+
+                paths.push((relative_path, is_dir));
+            }
+        }
+
+        Ok(paths)
+    })
+    .await??;
+
+    Ok(paths)
+}
@@ -0,0 +1,42 @@
+use crate::state::State;
+
+/// Check what the current host supports for running builds, and print how
+/// IDs get mapped and which mount backend builds will use (and why the
+/// others can't be used).
+pub async fn doctor(state: &State) -> anyhow::Result<()> {
+    let uname = nix::sys::utsname::uname()?;
+    println!(
+        "Kernel: {} {}",
+        uname.sysname().to_string_lossy(),
+        uname.release().to_string_lossy()
+    );
+
+    let id_mapping = crate::id_map::IdMapping::detect()?;
+    println!("ID mapping: {}", id_mapping);
+    if !id_mapping.uses_helpers() {
+        println!("  Build tools that call setgroups (like su) will fail in the sandbox. Install newuidmap and newgidmap to allow it");
+    }
+
+    let backends = crate::mount_backend::probe_backends(state).await?;
+
+    println!("Mount backends:");
+    for backend_support in &backends {
+        match &backend_support.support {
+            Ok(()) => {
+                println!("  {}: available", backend_support.backend);
+            }
+            Err(error) => {
+                println!("  {}: unavailable ({:#})", backend_support.backend, error);
+            }
+        }
+    }
+
+    let selected_backend = backends
+        .iter()
+        .find(|backend_support| backend_support.support.is_ok());
+    if let Some(selected_backend) = selected_backend {
+        println!("Builds will use {}", selected_backend.backend);
+    }
+
+    Ok(())
+}
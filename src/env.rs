@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    bake::{BakeOptions, BakedRecipe},
+    event::{Event, MessageLevel},
+    recipe::{ResolvedRecipeRef, ResolvedRecipeSet},
+    sandbox::SandboxProfile,
+    state::State,
+};
+
+/// Search path variables set for a host shell, along with the prefix
+/// subdirectory each one points to.
+const HOST_SEARCH_PATHS: &[(&str, &str)] = &[
+    ("PATH", "bin"),
+    ("LD_LIBRARY_PATH", "lib"),
+    ("LIBRARY_PATH", "lib"),
+    ("CPATH", "include"),
+    ("PKG_CONFIG_PATH", "lib/pkgconfig"),
+    ("MANPATH", "share/man"),
+];
+
+#[derive(Debug, Clone)]
+pub struct EnvOptions {
+    /// The project dir to work in, mounted as the source dir when running
+    /// inside the bootstrap env.
+    pub source_dir: PathBuf,
+    /// Start the shell on the host instead of inside the bootstrap env.
+    pub host: bool,
+}
+
+/// Bake a recipe's dependencies (but not the recipe itself), then start an
+/// interactive shell with them available. Returns the exit code of the
+/// shell.
+pub async fn env(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    bake_options: &BakeOptions,
+    options: &EnvOptions,
+) -> anyhow::Result<i32> {
+    let recipe = recipe_set.get(recipe_ref);
+    let dependency_recipes =
+        crate::bake::get_baked_dependencies(state, recipe_set, recipe_ref, bake_options).await?;
+
+    state.emit(Event::Message {
+        level: MessageLevel::Info,
+        message: format!(
+            "Starting environment for recipe {} {} with {} dependencies",
+            recipe.name,
+            recipe.version,
+            dependency_recipes.len()
+        ),
+        details: vec![],
+    });
+
+    if options.host {
+        env_on_host(state, recipe_set, recipe_ref, &dependency_recipes, options).await
+    } else {
+        env_in_sandbox(
+            state,
+            recipe_set,
+            recipe_ref,
+            &dependency_recipes,
+            bake_options.sandbox_profile,
+            options,
+        )
+        .await
+    }
+}
+
+async fn env_on_host(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    dependency_recipes: &[BakedRecipe],
+    options: &EnvOptions,
+) -> anyhow::Result<i32> {
+    let recipe = recipe_set.get(recipe_ref);
+
+    // Dependency layers are ordered with the highest precedence last, but
+    // search paths are checked from first to last
+    let dependency_layers =
+        crate::conflicts::order_dependency_layers(state, recipe_set, recipe, dependency_recipes)
+            .await?;
+    let prefixes: Vec<&Path> = dependency_layers
+        .iter()
+        .rev()
+        .map(|dependency| &*dependency.prefix_path)
+        .collect();
+
+    let shell = std::env::var_os("SHELL").unwrap_or_else(|| "/bin/sh".into());
+    let mut command = tokio::process::Command::new(shell);
+    command.current_dir(&options.source_dir);
+    for (var, subdir) in HOST_SEARCH_PATHS {
+        let search_path = crate::run::search_path(&prefixes, subdir, std::env::var_os(var))?;
+        command.env(var, search_path);
+    }
+    command.env("BRIOCHE_ENV", &recipe.name);
+
+    // Don't leave the shell running if brioche gets cancelled
+    command.kill_on_drop(true);
+
+    let mut child = command.spawn()?;
+    let exit_status = crate::bootstrap_env::wait_interactive(child.wait()).await?;
+
+    crate::bootstrap_env::host_exit_code(exit_status)
+}
+
+async fn env_in_sandbox(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    dependency_recipes: &[BakedRecipe],
+    sandbox_profile: SandboxProfile,
+    options: &EnvOptions,
+) -> anyhow::Result<i32> {
+    let recipe = recipe_set.get(recipe_ref);
+
+    let mut bootstrap_env = crate::bootstrap_env::BootstrapEnv::new(state).await?;
+    bootstrap_env.set_sandbox_profile(sandbox_profile);
+    crate::bake::add_dependency_layers(
+        state,
+        recipe_set,
+        recipe_ref,
+        dependency_recipes,
+        &mut bootstrap_env,
+    )
+    .await?;
+    bootstrap_env.mount_source(&options.source_dir);
+
+    let mut command = crate::bake::build_command(&bootstrap_env);
+    command.arg("-i");
+    command.env("BRIOCHE_ENV", &recipe.name);
+    command.inherit_stdio();
+
+    let child = bootstrap_env.spawn(&command)?;
+    let exit_status = child.wait_interactive().await?;
+
+    Ok(crate::bootstrap_env::exit_code(&exit_status))
+}
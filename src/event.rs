@@ -0,0 +1,289 @@
+use std::{
+    fmt::Debug,
+    io::Write as _,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use url::Url;
+
+use crate::{hash::Hash, recipe::ResolvedRecipeRef};
+
+/// Something that happened while resolving or baking recipes.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    ResolveStarted {
+        name: String,
+    },
+    ResolveFinished {
+        name: String,
+        version: String,
+        recipe_ref: ResolvedRecipeRef,
+    },
+    ResolveFailed {
+        name: String,
+        error: String,
+    },
+    DownloadStarted {
+        url: Url,
+        total_bytes: Option<u64>,
+    },
+    DownloadProgress {
+        url: Url,
+        downloaded_bytes: u64,
+        total_bytes: Option<u64>,
+    },
+    DownloadFinished {
+        url: Url,
+        content_hash: Hash,
+        path: PathBuf,
+    },
+    DownloadFailed {
+        url: Url,
+        error: String,
+    },
+    CheckoutFinished {
+        repo: Url,
+        git_ref: String,
+        commit: String,
+        path: PathBuf,
+    },
+    Unpacked {
+        content_hash: Hash,
+        path: PathBuf,
+    },
+    /// A recipe didn't need to be baked because it's already in the store.
+    CacheHit {
+        recipe_ref: ResolvedRecipeRef,
+        name: String,
+        version: String,
+    },
+    BuildStarted {
+        recipe_ref: ResolvedRecipeRef,
+        name: String,
+        version: String,
+    },
+    LogLine {
+        recipe_ref: ResolvedRecipeRef,
+        stream: LogStream,
+        line: String,
+    },
+    BuildFinished {
+        recipe_ref: ResolvedRecipeRef,
+        name: String,
+        version: String,
+        #[serde(with = "duration_secs")]
+        duration: Duration,
+        prefix_path: PathBuf,
+    },
+    BuildFailed {
+        recipe_ref: ResolvedRecipeRef,
+        name: String,
+        version: String,
+        #[serde(with = "duration_secs")]
+        duration: Duration,
+        error: String,
+    },
+    LockfileUpdated {
+        updated: bool,
+    },
+    /// The recipe requested by `brioche build` is ready.
+    RecipeReady {
+        recipe_ref: ResolvedRecipeRef,
+        name: String,
+        version: String,
+        prefix_path: PathBuf,
+    },
+    /// Anything else worth telling the user about, with `details` listed
+    /// below the message (such as affected paths).
+    Message {
+        level: MessageLevel,
+        message: String,
+        details: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageLevel {
+    Info,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+/// Receives events as they happen. Events can be emitted from any thread
+/// (including from blocking tasks).
+pub trait EventSink: Debug + Send + Sync {
+    fn emit(&self, event: Event);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Show live progress when attached to a terminal, or plain lines
+    /// otherwise.
+    Text,
+    /// Always print plain lines, including full build logs.
+    Plain,
+    /// Print each event as a line of JSON.
+    Json,
+}
+
+/// Prints events as human-readable lines, with build logs passed through to
+/// stdout and stderr.
+#[derive(Debug, Default)]
+pub struct TextEventSink;
+
+impl EventSink for TextEventSink {
+    fn emit(&self, event: Event) {
+        match event {
+            Event::ResolveStarted { .. }
+            | Event::ResolveFinished { .. }
+            | Event::ResolveFailed { .. }
+            | Event::DownloadStarted { .. }
+            | Event::DownloadProgress { .. } => {}
+            Event::DownloadFinished { url, path, .. } => {
+                println!("Downloaded URL {} -> {}", url, path.display());
+            }
+            Event::DownloadFailed { url, error } => {
+                eprintln!("Failed to download URL {}: {}", url, error);
+            }
+            Event::CheckoutFinished {
+                repo,
+                git_ref,
+                path,
+                ..
+            } => {
+                println!(
+                    "Checked out repo {} @ {} -> {}",
+                    repo,
+                    git_ref,
+                    path.display()
+                );
+            }
+            Event::Unpacked { content_hash, path } => {
+                println!("Unpacked {} -> {}", content_hash, path.display());
+            }
+            Event::CacheHit { name, version, .. } => {
+                println!("Recipe {} {} already baked", name, version);
+            }
+            Event::BuildStarted { name, version, .. } => {
+                println!("Baking recipe {} {}", name, version);
+            }
+            Event::LogLine { stream, line, .. } => match stream {
+                LogStream::Stdout => {
+                    let stdout = std::io::stdout();
+                    let _ = writeln!(stdout.lock(), "{}", line);
+                }
+                LogStream::Stderr => {
+                    let stderr = std::io::stderr();
+                    let _ = writeln!(stderr.lock(), "{}", line);
+                }
+            },
+            Event::BuildFinished {
+                name,
+                version,
+                duration,
+                ..
+            } => {
+                println!(
+                    "Baked recipe {} {} in {}",
+                    name,
+                    version,
+                    crate::format::format_duration(duration)
+                );
+            }
+            Event::BuildFailed {
+                name,
+                version,
+                duration,
+                ..
+            } => {
+                eprintln!(
+                    "Failed to bake recipe {} {} after {}",
+                    name,
+                    version,
+                    crate::format::format_duration(duration)
+                );
+            }
+            Event::LockfileUpdated { updated: true } => {
+                println!("Updated lockfile");
+            }
+            Event::LockfileUpdated { updated: false } => {
+                println!("Lockfile already up to date");
+            }
+            Event::RecipeReady {
+                name,
+                version,
+                prefix_path,
+                ..
+            } => {
+                println!("Built {} {} to {}", name, version, prefix_path.display());
+            }
+            Event::Message {
+                level,
+                message,
+                details,
+            } => {
+                match level {
+                    MessageLevel::Info => eprintln!("{}", message),
+                    MessageLevel::Warning => eprintln!("Warning: {}", message),
+                }
+                for detail in details {
+                    eprintln!("  {}", detail);
+                }
+            }
+        }
+    }
+}
+
+/// Writes each event to stdout as a line of JSON.
+#[derive(Debug, Default)]
+pub struct JsonEventSink;
+
+impl EventSink for JsonEventSink {
+    fn emit(&self, event: Event) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let json_event = JsonEvent {
+            timestamp: timestamp.as_secs_f64(),
+            event: &event,
+        };
+
+        let json = match serde_json::to_string(&json_event) {
+            Ok(json) => json,
+            Err(error) => {
+                eprintln!("failed to serialize event: {}", error);
+                return;
+            }
+        };
+
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = writeln!(stdout, "{}", json);
+        let _ = stdout.flush();
+    }
+}
+
+#[derive(serde::Serialize)]
+struct JsonEvent<'a> {
+    timestamp: f64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+mod duration_secs {
+    pub fn serialize<S>(duration: &std::time::Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+}
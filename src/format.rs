@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+
+    format!("{:.1} {}", size, unit)
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else if secs < 60 * 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else if secs < 24 * 60 * 60 {
+        format!("{}h {}m", secs / (60 * 60), (secs / 60) % 60)
+    } else {
+        format!("{}d {}h", secs / (24 * 60 * 60), (secs / (60 * 60)) % 24)
+    }
+}
+
+/// Parse a size like `512M` or `4GiB`. Units are powers of 1024, to match
+/// `format_size`.
+pub fn parse_size(size: &str) -> anyhow::Result<u64> {
+    let size = size.trim();
+    let unit_start = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(unit_start);
+
+    let number: u64 = match number.parse() {
+        Ok(number) => number,
+        Err(_) => {
+            anyhow::bail!("invalid size {:?}", size);
+        }
+    };
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        unit => {
+            anyhow::bail!("unknown size unit {:?} (expected B, K, M, G, or T)", unit);
+        }
+    };
+
+    match number.checked_mul(multiplier) {
+        Some(size) => Ok(size),
+        None => {
+            anyhow::bail!("size {:?} is too large", size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_size;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("512B").unwrap(), 512);
+        assert_eq!(parse_size("4K").unwrap(), 4 * 1024);
+        assert_eq!(parse_size("512M").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(parse_size("4GiB").unwrap(), 4 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size(" 2 T ").unwrap(), 2 << 40);
+    }
+
+    #[test]
+    fn test_parse_size_invalid() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("4X").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+}
@@ -0,0 +1,415 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::fs;
+
+use crate::{
+    event::{Event, MessageLevel},
+    format::format_size,
+    hash::Hash,
+    recipe::{ResolvedRecipeRef, ResolvedRecipeSourceRef},
+    state::{work_dir_lock_path, State},
+};
+
+pub struct GcOptions {
+    pub dry_run: bool,
+    pub keep_recent: Duration,
+}
+
+pub async fn collect_garbage(state: &State, options: &GcOptions) -> anyhow::Result<()> {
+    let keep_since = SystemTime::now()
+        .checked_sub(options.keep_recent)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut live_content_hashes = state.locked_content_hashes().await;
+    let live_git_commits = state.locked_git_commits().await;
+    let live_git_tree_hashes = state.locked_git_tree_hashes().await;
+
+    let roots = recipe_roots(
+        state,
+        keep_since,
+        &live_content_hashes,
+        &live_git_tree_hashes,
+    )
+    .await?;
+
+    // Walk from the roots through the stored recipe metadata to find every
+    // recipe (and recipe source) that is still reachable
+    let mut live_recipes = BTreeSet::new();
+    let mut recipes_without_metadata = vec![];
+    let mut pending_recipes: Vec<_> = roots.into_iter().collect();
+    while let Some(recipe_ref) = pending_recipes.pop() {
+        if !live_recipes.insert(recipe_ref) {
+            continue;
+        }
+
+        let recipe = match state.get_recipe_metadata(&recipe_ref).await? {
+            Some(recipe) => recipe,
+            None => {
+                recipes_without_metadata.push(recipe_ref);
+                continue;
+            }
+        };
+
+        if let ResolvedRecipeSourceRef::Tarball { hash } = recipe.source {
+            live_content_hashes.insert(hash);
+        }
+        // Build dependencies are kept too, so recipes can be rebaked
+        // without rebuilding their toolchains
+        pending_recipes.extend(recipe.dependencies.iter().copied());
+        pending_recipes.extend(recipe.build_dependencies.iter().copied());
+    }
+
+    let mut garbage_paths = vec![];
+
+    // Without a recipe's metadata (e.g. for recipes baked by older
+    // versions), there's no way to tell which recipes it depends on, so
+    // keep every recipe rather than breaking it
+    if recipes_without_metadata.is_empty() {
+        for (entry_name, entry_path) in read_dir_entries(&state.recipes_dir).await? {
+            if let Ok(recipe_ref) = entry_name.parse::<ResolvedRecipeRef>() {
+                if !live_recipes.contains(&recipe_ref) {
+                    garbage_paths.push(entry_path);
+                }
+            }
+        }
+    } else {
+        state.emit(Event::Message {
+            level: MessageLevel::Info,
+            message: "Keeping all recipes, since the dependencies of these recipes are unknown (no recipe.json):".to_string(),
+            details: recipes_without_metadata
+                .iter()
+                .map(|recipe_ref| recipe_ref.to_string())
+                .collect(),
+        });
+    }
+
+    for (entry_name, entry_path) in read_dir_entries(&state.downloads_dir).await? {
+        if let Ok(content_hash) = entry_name.parse::<Hash>() {
+            if !live_content_hashes.contains(&content_hash) {
+                garbage_paths.push(entry_path);
+            }
+        }
+    }
+    for (_, entry_path) in read_dir_entries(&state.temp_downloads_dir).await? {
+        garbage_paths.push(entry_path);
+    }
+
+    for (entry_name, entry_path) in read_dir_entries(&state.checkouts_dir).await? {
+        if entry_path == state.temp_checkouts_dir {
+            continue;
+        }
+
+        if !live_git_commits.contains(&entry_name) {
+            garbage_paths.push(entry_path);
+        }
+    }
+    for (_, entry_path) in read_dir_entries(&state.temp_checkouts_dir).await? {
+        garbage_paths.push(entry_path);
+    }
+
+    for (entry_name, entry_path) in read_dir_entries(&state.unpack_dir).await? {
+        match entry_name.parse::<Hash>() {
+            Ok(content_hash) if !live_content_hashes.contains(&content_hash) => {
+                garbage_paths.push(entry_path);
+            }
+            _ => {
+                // Clear out leftovers from interrupted unpacks
+                let temp_dir = entry_path.join("temp");
+                if temp_dir.exists() {
+                    garbage_paths.push(temp_dir);
+                }
+            }
+        }
+    }
+
+    // Work dirs are locked while they're in use. Unlocked work dirs were
+    // kept after a failed build, so only remove them once they're old
+    for (entry_name, entry_path) in read_dir_entries(&state.temp_work_dirs_dir).await? {
+        if entry_name.ends_with(".lock") || is_work_dir_locked(&entry_path)? {
+            continue;
+        }
+
+        if !modified_since(&entry_path, keep_since).await? {
+            let lock_path = work_dir_lock_path(&entry_path);
+            if lock_path.exists() {
+                garbage_paths.push(lock_path);
+            }
+            garbage_paths.push(entry_path);
+        }
+    }
+
+    let mut removed_paths = vec![];
+    let mut total_size = 0;
+    for path in &garbage_paths {
+        let size = path_size(path).await?;
+
+        if !options.dry_run {
+            if let Err(error) = remove_path(path).await {
+                state.emit(Event::Message {
+                    level: MessageLevel::Warning,
+                    message: format!("Failed to remove {}: {:#}", path.display(), error),
+                    details: vec![],
+                });
+                continue;
+            }
+        }
+
+        removed_paths.push(format!("{} ({})", path.display(), format_size(size)));
+        total_size += size;
+    }
+
+    let message = if options.dry_run {
+        format!(
+            "Would remove {} paths, freeing {}",
+            removed_paths.len(),
+            format_size(total_size)
+        )
+    } else {
+        format!(
+            "Removed {} paths, freed {}",
+            removed_paths.len(),
+            format_size(total_size)
+        )
+    };
+    state.emit(Event::Message {
+        level: MessageLevel::Info,
+        message,
+        details: removed_paths,
+    });
+
+    Ok(())
+}
+
+pub async fn pin(state: &State, recipe_ref: &ResolvedRecipeRef) -> anyhow::Result<()> {
+    let recipe_dir = state.recipes_dir.join(recipe_ref.to_path_component());
+    if state.get_recipe_output(recipe_ref)?.is_none() {
+        anyhow::bail!("recipe {} has not been baked", recipe_ref);
+    }
+
+    let gc_root_path = state.gc_roots_dir.join(recipe_ref.to_path_component());
+    let _ = fs::remove_file(&gc_root_path).await;
+    fs::symlink(&recipe_dir, &gc_root_path).await?;
+
+    Ok(())
+}
+
+pub async fn unpin(state: &State, recipe_ref: &ResolvedRecipeRef) -> anyhow::Result<()> {
+    let gc_root_path = state.gc_roots_dir.join(recipe_ref.to_path_component());
+    match fs::remove_file(&gc_root_path).await {
+        Ok(()) => Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            anyhow::bail!("recipe {} is not pinned", recipe_ref);
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Find the recipes that should be kept along with all of their
+/// dependencies: recipes pinned with a symlink in the GC roots dir, recipes
+/// installed in a profile generation linked from the GC roots dir, recipes
+/// built from a source in the lockfile, and recipes that were baked
+/// recently.
+async fn recipe_roots(
+    state: &State,
+    keep_since: SystemTime,
+    locked_content_hashes: &HashSet<Hash>,
+    locked_git_tree_hashes: &HashSet<Hash>,
+) -> anyhow::Result<HashSet<ResolvedRecipeRef>> {
+    let mut roots = HashSet::new();
+
+    for (_, entry_path) in read_dir_entries(&state.gc_roots_dir).await? {
+        let target = match fs::read_link(&entry_path).await {
+            Ok(target) => target,
+            Err(_) => {
+                continue;
+            }
+        };
+
+        if target.starts_with(&state.profile_dir) {
+            // Skip generations that have been deleted
+            if let Ok(manifest) = crate::profile::read_manifest(&target).await {
+                roots.extend(
+                    manifest
+                        .recipes
+                        .values()
+                        .map(|installed| installed.recipe_ref),
+                );
+            }
+            continue;
+        }
+
+        let recipe_ref = target
+            .strip_prefix(&state.recipes_dir)
+            .ok()
+            .and_then(|recipe_path| recipe_path.iter().next())
+            .and_then(|component| component.to_str())
+            .and_then(|component| component.parse().ok());
+        if let Some(recipe_ref) = recipe_ref {
+            roots.insert(recipe_ref);
+        }
+    }
+
+    for (entry_name, entry_path) in read_dir_entries(&state.recipes_dir).await? {
+        let recipe_ref = match entry_name.parse() {
+            Ok(recipe_ref) => recipe_ref,
+            Err(_) => {
+                continue;
+            }
+        };
+
+        if modified_since(&entry_path, keep_since).await? {
+            roots.insert(recipe_ref);
+            continue;
+        }
+
+        let recipe = match state.get_recipe_metadata(&recipe_ref).await? {
+            Some(recipe) => recipe,
+            None => {
+                continue;
+            }
+        };
+        let is_locked = match recipe.source {
+            ResolvedRecipeSourceRef::Tarball { hash } => locked_content_hashes.contains(&hash),
+            ResolvedRecipeSourceRef::Git { hash } => locked_git_tree_hashes.contains(&hash),
+        };
+        if is_locked {
+            roots.insert(recipe_ref);
+        }
+    }
+
+    Ok(roots)
+}
+
+async fn read_dir_entries(dir: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(vec![]);
+        }
+        Err(error) => {
+            return Err(error.into());
+        }
+    };
+
+    let mut result = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let entry_name = entry.file_name().to_string_lossy().into_owned();
+        result.push((entry_name, entry.path()));
+    }
+
+    result.sort();
+    Ok(result)
+}
+
+/// Check if another process holds the lock on a work dir.
+fn is_work_dir_locked(dir: &Path) -> anyhow::Result<bool> {
+    use std::os::unix::io::AsRawFd as _;
+
+    let lock_file = match std::fs::File::open(work_dir_lock_path(dir)) {
+        Ok(lock_file) => lock_file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(false);
+        }
+        Err(error) => {
+            return Err(error.into());
+        }
+    };
+
+    match nix::fcntl::flock(
+        lock_file.as_raw_fd(),
+        nix::fcntl::FlockArg::LockExclusiveNonblock,
+    ) {
+        Ok(()) => Ok(false),
+        Err(nix::errno::Errno::EWOULDBLOCK) => Ok(true),
+        Err(error) => Err(error.into()),
+    }
+}
+
+async fn modified_since(path: &Path, since: SystemTime) -> anyhow::Result<bool> {
+    let metadata = fs::symlink_metadata(path).await?;
+    Ok(metadata.modified()? >= since)
+}
+
+async fn path_size(path: &Path) -> anyhow::Result<u64> {
+    let path = path.to_owned();
+    let size = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let mut size = 0;
+        let mut pending_paths = vec![path];
+        while let Some(path) = pending_paths.pop() {
+            let metadata = std::fs::symlink_metadata(&path)?;
+            size += metadata.len();
+
+            if metadata.is_dir() {
+                for entry in std::fs::read_dir(&path)? {
+                    pending_paths.push(entry?.path());
+                }
+            }
+        }
+
+        Ok(size)
+    })
+    .await??;
+
+    Ok(size)
+}
+
+async fn remove_path(path: &Path) -> anyhow::Result<()> {
+    let metadata = fs::symlink_metadata(path).await?;
+    if !metadata.is_dir() {
+        fs::remove_file(path).await?;
+        return Ok(());
+    }
+
+    let remove_result = fs::remove_dir_all(path).await;
+    match remove_result {
+        Ok(()) => Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::PermissionDenied => {
+            // Builds can leave behind read-only directories, so make
+            // everything writable and try again
+            let chmod_result = tokio::process::Command::new("chmod")
+                .arg("-R")
+                .arg("u+w")
+                .arg(path)
+                .status()
+                .await?;
+            if !chmod_result.success() {
+                anyhow::bail!("chmod exited with status {}", chmod_result);
+            }
+
+            fs::remove_dir_all(path).await?;
+            Ok(())
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd as _;
+
+    use super::is_work_dir_locked;
+    use crate::state::work_dir_lock_path;
+
+    #[test]
+    fn test_is_work_dir_locked() {
+        let dir = std::env::temp_dir().join(format!("brioche-gc-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(!is_work_dir_locked(&dir).unwrap());
+
+        let lock_file = std::fs::File::create(work_dir_lock_path(&dir)).unwrap();
+        assert!(!is_work_dir_locked(&dir).unwrap());
+
+        nix::fcntl::flock(lock_file.as_raw_fd(), nix::fcntl::FlockArg::LockExclusive).unwrap();
+        assert!(is_work_dir_locked(&dir).unwrap());
+
+        drop(lock_file);
+        assert!(!is_work_dir_locked(&dir).unwrap());
+
+        std::fs::remove_file(work_dir_lock_path(&dir)).unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
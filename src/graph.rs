@@ -0,0 +1,289 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use crate::{
+    recipe::{ResolvedRecipeRef, ResolvedRecipeSet},
+    state::State,
+};
+
+/// The maximum number of dependency paths to list for `brioche why`.
+const MAX_REPORTED_PATHS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GraphFormat {
+    Text,
+    Dot,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum DependencyKind {
+    Runtime,
+    Build,
+}
+
+/// Print the resolved dependency graph of a recipe.
+pub async fn graph(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    root_ref: &ResolvedRecipeRef,
+    format: GraphFormat,
+) -> anyhow::Result<()> {
+    let recipe_refs = reachable_recipes(recipe_set, root_ref);
+
+    let mut baked = HashSet::new();
+    for recipe_ref in &recipe_refs {
+        if state.get_recipe_output(recipe_ref)?.is_some() {
+            baked.insert(*recipe_ref);
+        }
+    }
+
+    match format {
+        GraphFormat::Text => {
+            let mut shown = HashSet::new();
+            print_tree(recipe_set, &baked, root_ref, None, "", "", &mut shown);
+        }
+        GraphFormat::Dot => {
+            println!("digraph brioche {{");
+            for recipe_ref in &recipe_refs {
+                let recipe = recipe_set.get(recipe_ref);
+                let style = if baked.contains(recipe_ref) {
+                    "solid"
+                } else {
+                    "dashed"
+                };
+                println!(
+                    "  \"{}\" [label=\"{} {}\\n{}\\n{}\", style={}];",
+                    recipe_ref,
+                    escape_dot(&recipe.name),
+                    escape_dot(&recipe.version),
+                    short_hash(recipe_ref),
+                    recipe.source,
+                    style
+                );
+            }
+            for recipe_ref in &recipe_refs {
+                for (dependency_ref, kind) in dependencies(recipe_set, recipe_ref) {
+                    let style = match kind {
+                        DependencyKind::Runtime => "solid",
+                        DependencyKind::Build => "dashed",
+                    };
+                    println!(
+                        "  \"{}\" -> \"{}\" [style={}];",
+                        recipe_ref, dependency_ref, style
+                    );
+                }
+            }
+            println!("}}");
+        }
+        GraphFormat::Json => {
+            let recipes: BTreeMap<_, _> = recipe_refs
+                .iter()
+                .map(|recipe_ref| {
+                    let recipe = recipe_set.get(recipe_ref);
+                    let value = serde_json::json!({
+                        "name": recipe.name,
+                        "version": recipe.version,
+                        "source": recipe.source,
+                        "baked": baked.contains(recipe_ref),
+                        "dependencies": recipe.dependencies,
+                        "buildDependencies": recipe.build_dependencies,
+                    });
+                    (recipe_ref.to_string(), value)
+                })
+                .collect();
+            let value = serde_json::json!({
+                "root": root_ref,
+                "recipes": recipes,
+            });
+            println!("{}", serde_json::to_string_pretty(&value)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print every dependency path from one recipe to another recipe in its
+/// graph, matched by name or hash.
+pub fn why(
+    recipe_set: &ResolvedRecipeSet,
+    root_ref: &ResolvedRecipeRef,
+    target: &str,
+) -> anyhow::Result<()> {
+    let root = recipe_set.get(root_ref);
+    let target_refs: BTreeSet<_> = reachable_recipes(recipe_set, root_ref)
+        .into_iter()
+        .filter(|recipe_ref| {
+            *recipe_ref != *root_ref
+                && (recipe_set.get(recipe_ref).name == target || recipe_ref.to_string() == target)
+        })
+        .collect();
+    if target_refs.is_empty() {
+        anyhow::bail!(
+            "{} {} does not depend on {}",
+            root.name,
+            root.version,
+            target
+        );
+    }
+
+    let mut paths = vec![];
+    let mut current_path = vec![(*root_ref, None)];
+    find_paths(recipe_set, &target_refs, &mut current_path, &mut paths);
+
+    println!(
+        "{} {} depends on {} through {} paths:",
+        root.name,
+        root.version,
+        target,
+        paths.len()
+    );
+    for path in paths.iter().take(MAX_REPORTED_PATHS) {
+        let mut line = String::new();
+        for (index, (recipe_ref, kind)) in path.iter().enumerate() {
+            let recipe = recipe_set.get(recipe_ref);
+            if index > 0 {
+                match kind {
+                    Some(DependencyKind::Build) => line.push_str(" -[build]-> "),
+                    _ => line.push_str(" -> "),
+                }
+            }
+            line.push_str(&format!("{} {}", recipe.name, recipe.version));
+        }
+        println!("  {}", line);
+    }
+    if paths.len() > MAX_REPORTED_PATHS {
+        println!("  ...and {} more", paths.len() - MAX_REPORTED_PATHS);
+    }
+
+    Ok(())
+}
+
+type DependencyPath = Vec<(ResolvedRecipeRef, Option<DependencyKind>)>;
+
+fn find_paths(
+    recipe_set: &ResolvedRecipeSet,
+    target_refs: &BTreeSet<ResolvedRecipeRef>,
+    current_path: &mut DependencyPath,
+    paths: &mut Vec<DependencyPath>,
+) {
+    let (recipe_ref, _) = *current_path.last().expect("dependency path is empty");
+    for (dependency_ref, kind) in dependencies(recipe_set, &recipe_ref) {
+        current_path.push((dependency_ref, Some(kind)));
+        if target_refs.contains(&dependency_ref) {
+            paths.push(current_path.clone());
+        } else {
+            find_paths(recipe_set, target_refs, current_path, paths);
+        }
+        current_path.pop();
+    }
+}
+
+fn print_tree(
+    recipe_set: &ResolvedRecipeSet,
+    baked: &HashSet<ResolvedRecipeRef>,
+    recipe_ref: &ResolvedRecipeRef,
+    kind: Option<DependencyKind>,
+    prefix: &str,
+    child_prefix: &str,
+    shown: &mut HashSet<ResolvedRecipeRef>,
+) {
+    let recipe = recipe_set.get(recipe_ref);
+    let kind_label = match kind {
+        Some(DependencyKind::Build) => "[build] ",
+        _ => "",
+    };
+    let baked_label = if baked.contains(recipe_ref) {
+        "baked"
+    } else {
+        "not baked"
+    };
+
+    let is_first = shown.insert(*recipe_ref);
+    let dependencies = dependencies(recipe_set, recipe_ref);
+    let repeated_label = if !is_first && !dependencies.is_empty() {
+        " (see above)"
+    } else {
+        ""
+    };
+
+    println!(
+        "{}{}{} {} ({}, {}, {}){}",
+        prefix,
+        kind_label,
+        recipe.name,
+        recipe.version,
+        short_hash(recipe_ref),
+        recipe.source,
+        baked_label,
+        repeated_label
+    );
+
+    // Only expand each recipe the first time it's shown
+    if !is_first {
+        return;
+    }
+
+    for (index, (dependency_ref, kind)) in dependencies.iter().enumerate() {
+        let is_last = index + 1 == dependencies.len();
+        let (branch, continuation) = if is_last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        print_tree(
+            recipe_set,
+            baked,
+            dependency_ref,
+            Some(*kind),
+            &format!("{}{}", child_prefix, branch),
+            &format!("{}{}", child_prefix, continuation),
+            shown,
+        );
+    }
+}
+
+/// List a recipe's direct dependencies, runtime dependencies first.
+fn dependencies(
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+) -> Vec<(ResolvedRecipeRef, DependencyKind)> {
+    let recipe = recipe_set.get(recipe_ref);
+    let runtime = recipe
+        .dependencies
+        .iter()
+        .map(|dependency_ref| (*dependency_ref, DependencyKind::Runtime));
+    let build = recipe
+        .build_dependencies
+        .iter()
+        .map(|dependency_ref| (*dependency_ref, DependencyKind::Build));
+    runtime.chain(build).collect()
+}
+
+fn reachable_recipes(
+    recipe_set: &ResolvedRecipeSet,
+    root_ref: &ResolvedRecipeRef,
+) -> BTreeSet<ResolvedRecipeRef> {
+    let mut recipe_refs = BTreeSet::new();
+    let mut pending_refs = vec![*root_ref];
+    while let Some(recipe_ref) = pending_refs.pop() {
+        if recipe_refs.insert(recipe_ref) {
+            pending_refs.extend(
+                dependencies(recipe_set, &recipe_ref)
+                    .into_iter()
+                    .map(|(dependency_ref, _)| dependency_ref),
+            );
+        }
+    }
+
+    recipe_refs
+}
+
+fn short_hash(recipe_ref: &ResolvedRecipeRef) -> String {
+    let mut hash = recipe_ref.to_string();
+    hash.truncate(12);
+    hash
+}
+
+fn escape_dot(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
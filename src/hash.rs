@@ -1,6 +1,7 @@
 use std::{
     fmt::{Debug, Display},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use serde::{Deserialize, Serialize};
@@ -40,6 +41,17 @@ impl Debug for Hash {
     }
 }
 
+impl FromStr for Hash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; SHA256_DIGEST_SIZE];
+        hex::decode_to_slice(s, &mut bytes)
+            .map_err(|error| anyhow::anyhow!("invalid hash {:?}: {}", s, error))?;
+        Ok(Self { bytes })
+    }
+}
+
 impl Display for Hash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         // TODO: Write without using a temporary buffer. See:
@@ -51,3 +63,155 @@ impl Display for Hash {
         f.write_str(hex_str)
     }
 }
+
+/// Which permission bits contribute to a tree hash.
+#[derive(Debug, Clone, Copy)]
+enum ModeBits {
+    /// Only whether each file is executable, for trees whose other bits
+    /// depend on the host (like git checkouts, which depend on the umask).
+    Executable,
+    /// The full mode of each file and directory (`mode & 0o7777`), for
+    /// trees with normalized permissions (like baked outputs).
+    Full,
+}
+
+/// Compute a content hash for a directory tree from its names, contents,
+/// symlink targets and executable bits (like a NAR archive).
+pub async fn hash_dir(path: impl AsRef<Path>) -> anyhow::Result<Hash> {
+    hash_tree(path.as_ref(), b"brioche-tree-v0", ModeBits::Executable).await
+}
+
+/// Compute a content hash for a baked output. This works like `hash_dir`,
+/// except the full permissions of every file and directory (including the
+/// setuid, setgid and sticky bits) contribute to the hash too, so changes
+/// to them get caught when verifying the output.
+pub async fn hash_output_dir(path: impl AsRef<Path>) -> anyhow::Result<Hash> {
+    hash_tree(path.as_ref(), b"brioche-output-v0", ModeBits::Full).await
+}
+
+async fn hash_tree(
+    path: &Path,
+    header: &'static [u8],
+    mode_bits: ModeBits,
+) -> anyhow::Result<Hash> {
+    use sha2::Digest as _;
+
+    let path = path.to_owned();
+    let hash = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let mut tree_hash = sha2::Sha256::new();
+        tree_hash.update(header);
+        hash_tree_entry(&mut tree_hash, &path, mode_bits)?;
+        Ok(Hash::from_digest(tree_hash))
+    })
+    .await??;
+
+    Ok(hash)
+}
+
+fn hash_tree_entry(
+    tree_hash: &mut sha2::Sha256,
+    path: &Path,
+    mode_bits: ModeBits,
+) -> anyhow::Result<()> {
+    use sha2::Digest as _;
+    use std::os::unix::{ffi::OsStrExt as _, fs::PermissionsExt as _};
+
+    let metadata = std::fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        let target = std::fs::read_link(path)?;
+        let target = target.as_os_str().as_bytes();
+
+        tree_hash.update(b"symlink");
+        tree_hash.update((target.len() as u64).to_le_bytes());
+        tree_hash.update(target);
+    } else if file_type.is_file() {
+        tree_hash.update(b"file");
+        match mode_bits {
+            ModeBits::Executable => {
+                let is_executable = metadata.permissions().mode() & 0o111 != 0;
+                tree_hash.update([is_executable as u8]);
+            }
+            ModeBits::Full => {
+                tree_hash.update((metadata.permissions().mode() & 0o7777).to_le_bytes());
+            }
+        }
+        tree_hash.update(metadata.len().to_le_bytes());
+
+        let mut file = std::fs::File::open(path)?;
+        std::io::copy(&mut file, tree_hash)?;
+    } else if file_type.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+
+        tree_hash.update(b"directory");
+        if let ModeBits::Full = mode_bits {
+            tree_hash.update((metadata.permissions().mode() & 0o7777).to_le_bytes());
+        }
+        tree_hash.update((entries.len() as u64).to_le_bytes());
+        for entry in entries {
+            let name = entry.as_bytes();
+            tree_hash.update((name.len() as u64).to_le_bytes());
+            tree_hash.update(name);
+
+            hash_tree_entry(tree_hash, &path.join(&entry), mode_bits)?;
+        }
+    } else {
+        anyhow::bail!("unsupported file type at {}", path.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use super::*;
+
+    fn set_mode(path: &Path, mode: u32) {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_hash_output_dir_includes_mode() {
+        let dir = std::env::temp_dir().join(format!("brioche-hash-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("bin")).unwrap();
+        let file_path = dir.join("bin/tool");
+        std::fs::write(&file_path, "#!/bin/sh\n").unwrap();
+        set_mode(&dir, 0o755);
+        set_mode(&dir.join("bin"), 0o755);
+        set_mode(&file_path, 0o755);
+
+        let tree_hash = hash_dir(&dir).await.unwrap();
+        let output_hash = hash_output_dir(&dir).await.unwrap();
+        assert_ne!(tree_hash, output_hash);
+
+        // The setuid bit only changes the output hash
+        set_mode(&file_path, 0o4755);
+        assert_eq!(hash_dir(&dir).await.unwrap(), tree_hash);
+        let setuid_hash = hash_output_dir(&dir).await.unwrap();
+        assert_ne!(setuid_hash, output_hash);
+
+        // So do directory permissions
+        set_mode(&file_path, 0o755);
+        set_mode(&dir.join("bin"), 0o777);
+        assert_eq!(hash_dir(&dir).await.unwrap(), tree_hash);
+        let dir_mode_hash = hash_output_dir(&dir).await.unwrap();
+        assert_ne!(dir_mode_hash, output_hash);
+        assert_ne!(dir_mode_hash, setuid_hash);
+
+        // The executable bit changes both
+        set_mode(&dir.join("bin"), 0o755);
+        set_mode(&file_path, 0o644);
+        assert_ne!(hash_dir(&dir).await.unwrap(), tree_hash);
+        assert_ne!(hash_output_dir(&dir).await.unwrap(), output_hash);
+
+        set_mode(&file_path, 0o755);
+        assert_eq!(hash_output_dir(&dir).await.unwrap(), output_hash);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+/// How user and group IDs inside the sandbox map to IDs on the host. The
+/// current user is always root inside the sandbox. When the `newuidmap` and
+/// `newgidmap` helpers are installed and the user has subordinate IDs in
+/// `/etc/subuid` and `/etc/subgid`, those get mapped too (starting at ID 1),
+/// so builds can `chown` files to other users.
+///
+/// Without the helpers, the maps are written directly, which means
+/// `setgroups` has to be denied in the sandbox. Build tools that call it
+/// (like `su`, or package managers that drop privileges) fail there.
+#[derive(Debug, Clone)]
+pub struct IdMapping {
+    uid_maps: Vec<unshare::UidMap>,
+    gid_maps: Vec<unshare::GidMap>,
+    /// The `newuidmap` and `newgidmap` helpers, or `None` to write the maps
+    /// directly (which only works for the current user's own IDs).
+    helpers: Option<(PathBuf, PathBuf)>,
+    /// The host's `chown`, used to take back files owned by subordinate
+    /// IDs. Subordinate IDs are only mapped if it's installed.
+    chown: Option<PathBuf>,
+}
+
+impl IdMapping {
+    pub fn detect() -> anyhow::Result<Self> {
+        let current_uid = nix::unistd::Uid::current();
+        let current_gid = nix::unistd::Gid::current();
+
+        let mut uid_maps = vec![unshare::UidMap {
+            outside_uid: current_uid.as_raw(),
+            inside_uid: 0,
+            count: 1,
+        }];
+        let mut gid_maps = vec![unshare::GidMap {
+            outside_gid: current_gid.as_raw(),
+            inside_gid: 0,
+            count: 1,
+        }];
+
+        let helpers = match (which::which("newuidmap"), which::which("newgidmap")) {
+            (Ok(newuidmap), Ok(newgidmap)) => Some((newuidmap, newgidmap)),
+            _ => None,
+        };
+
+        let chown = which::which("chown").ok();
+
+        if helpers.is_some() && chown.is_some() {
+            let user_name = nix::unistd::User::from_uid(current_uid)?.map(|user| user.name);
+            let subuid_range = find_subordinate_range(
+                Path::new("/etc/subuid"),
+                user_name.as_deref(),
+                current_uid.as_raw(),
+            )?;
+            let subgid_range = find_subordinate_range(
+                Path::new("/etc/subgid"),
+                user_name.as_deref(),
+                current_uid.as_raw(),
+            )?;
+
+            // Only map subordinate IDs if there are both, so files can't
+            // end up owned by a user or group that doesn't exist on the host
+            if let (Some(subuid_range), Some(subgid_range)) = (subuid_range, subgid_range) {
+                uid_maps.push(unshare::UidMap {
+                    outside_uid: subuid_range.start,
+                    inside_uid: 1,
+                    count: subuid_range.count,
+                });
+                gid_maps.push(unshare::GidMap {
+                    outside_gid: subgid_range.start,
+                    inside_gid: 1,
+                    count: subgid_range.count,
+                });
+            }
+        }
+
+        Ok(Self {
+            uid_maps,
+            gid_maps,
+            helpers,
+            chown,
+        })
+    }
+
+    /// Check if the maps get written with the `newuidmap` and `newgidmap`
+    /// helpers, rather than directly with `write_maps`.
+    pub fn uses_helpers(&self) -> bool {
+        self.helpers.is_some()
+    }
+
+    /// Check if IDs other than the current user's are mapped, in which case
+    /// the sandbox can create files the current user can't remove.
+    pub fn has_subordinate_ids(&self) -> bool {
+        self.uid_maps.len() > 1
+    }
+
+    /// Make the current user the owner of everything in a dir again. With
+    /// subordinate IDs mapped, builds can `chown` files to IDs the current
+    /// user can't modify or remove, so this runs `chown` from a user
+    /// namespace with the same mapping (where the current user is root).
+    /// Blocks until `chown` exits.
+    pub fn reset_ownership(&self, dir: &Path) -> anyhow::Result<()> {
+        let chown = match &self.chown {
+            Some(chown) if self.has_subordinate_ids() => chown,
+            _ => {
+                return Ok(());
+            }
+        };
+
+        let mut chown_cmd = unshare::Command::new(chown);
+        chown_cmd.arg("-R").arg("-h").arg("0:0").arg(dir);
+        chown_cmd.unshare([&unshare::Namespace::User]);
+        self.set_id_maps(&mut chown_cmd);
+        chown_cmd.uid(0);
+        chown_cmd.gid(0);
+
+        let chown_status = chown_cmd
+            .spawn()
+            .map_err(|error| anyhow::anyhow!("failed to spawn chown: {}", error))?
+            .wait()?;
+        if !chown_status.success() {
+            anyhow::bail!("chown failed with exit code {}", chown_status);
+        }
+
+        Ok(())
+    }
+
+    /// Set up the maps for a command that unshares the user namespace, using
+    /// the helpers. Does nothing without the helpers, in which case the maps
+    /// need to be written with `write_maps` before the child is unfrozen.
+    pub fn set_id_maps(&self, spawn_cmd: &mut unshare::Command) {
+        if let Some((newuidmap, newgidmap)) = &self.helpers {
+            spawn_cmd.set_id_map_commands(newuidmap, newgidmap);
+            spawn_cmd.set_id_maps(self.uid_maps.clone(), self.gid_maps.clone());
+        }
+    }
+
+    /// Write the maps for a child directly, without the helpers. `setgroups`
+    /// has to be denied first, since unprivileged users can't write a GID map
+    /// otherwise.
+    pub fn write_maps(&self, pid: u32) -> std::io::Result<()> {
+        let proc_dir = PathBuf::from("/proc").join(pid.to_string());
+
+        std::fs::write(proc_dir.join("setgroups"), "deny")?;
+
+        let mut uid_map = vec![];
+        for map in &self.uid_maps {
+            writeln!(
+                uid_map,
+                "{} {} {}",
+                map.inside_uid, map.outside_uid, map.count
+            )?;
+        }
+        std::fs::write(proc_dir.join("uid_map"), uid_map)?;
+
+        let mut gid_map = vec![];
+        for map in &self.gid_maps {
+            writeln!(
+                gid_map,
+                "{} {} {}",
+                map.inside_gid, map.outside_gid, map.count
+            )?;
+        }
+        std::fs::write(proc_dir.join("gid_map"), gid_map)?;
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for IdMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.helpers, self.uid_maps.get(1)) {
+            (Some(_), Some(subuid_map)) => write!(
+                f,
+                "newuidmap/newgidmap, with {} subordinate IDs starting at {}",
+                subuid_map.count, subuid_map.outside_uid
+            ),
+            (Some(_), None) if self.chown.is_none() => write!(
+                f,
+                "newuidmap/newgidmap, current user only (chown not installed)"
+            ),
+            (Some(_), None) => write!(
+                f,
+                "newuidmap/newgidmap, current user only (no ranges in /etc/subuid and /etc/subgid)"
+            ),
+            (None, _) => write!(
+                f,
+                "written directly, current user only, setgroups denied (newuidmap/newgidmap not installed)"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SubordinateRange {
+    start: u32,
+    count: u32,
+}
+
+/// Find the first range for the current user in `/etc/subuid` or
+/// `/etc/subgid`. Entries can name the user either by name or by UID.
+fn find_subordinate_range(
+    path: &Path,
+    user_name: Option<&str>,
+    uid: u32,
+) -> anyhow::Result<Option<SubordinateRange>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(error) => {
+            return Err(error.into());
+        }
+    };
+
+    let uid = uid.to_string();
+    for line in content.lines() {
+        let mut fields = line.trim().split(':');
+        let (owner, start, count) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(owner), Some(start), Some(count)) => (owner, start, count),
+            _ => {
+                continue;
+            }
+        };
+
+        if owner != uid && Some(owner) != user_name {
+            continue;
+        }
+
+        let (start, count) = match (start.parse(), count.parse()) {
+            (Ok(start), Ok(count)) => (start, count),
+            _ => {
+                continue;
+            }
+        };
+        if count == 0 {
+            continue;
+        }
+
+        return Ok(Some(SubordinateRange { start, count }));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find_subordinate_range;
+
+    fn find_range(content: &str) -> Option<(u32, u32)> {
+        let path =
+            std::env::temp_dir().join(format!("brioche-subuid-test-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, content).unwrap();
+        let range = find_subordinate_range(&path, Some("alice"), 1000).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        range.map(|range| (range.start, range.count))
+    }
+
+    #[test]
+    fn test_find_subordinate_range() {
+        assert_eq!(find_range("alice:100000:65536\n"), Some((100000, 65536)));
+        assert_eq!(find_range("1000:200000:65536\n"), Some((200000, 65536)));
+        assert_eq!(
+            find_range("bob:100000:65536\nalice:165536:65536\n"),
+            Some((165536, 65536))
+        );
+        assert_eq!(find_range("bob:100000:65536\n"), None);
+    }
+
+    #[test]
+    fn test_find_subordinate_range_skips_invalid_entries() {
+        assert_eq!(
+            find_range("alice\nalice:x:65536\nalice:100000:0\nalice:200000:10\n"),
+            Some((200000, 10))
+        );
+        assert_eq!(find_range(""), None);
+    }
+
+    #[test]
+    fn test_find_subordinate_range_missing_file() {
+        let path =
+            std::env::temp_dir().join(format!("brioche-subuid-test-{}", uuid::Uuid::new_v4()));
+        let range = find_subordinate_range(&path, Some("alice"), 1000).unwrap();
+        assert!(range.is_none());
+    }
+}
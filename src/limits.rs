@@ -0,0 +1,423 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use joinery::JoinableIterator;
+
+use crate::{
+    event::{Event, EventSink, MessageLevel},
+    format::{format_size, parse_size},
+};
+
+/// How often to check how much disk space a running build is using. Checks
+/// get spaced out further for builds with so many files that counting them
+/// takes longer than this.
+const DISK_USAGE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The period used for the cgroup CPU limit, in microseconds.
+const CPU_PERIOD_MICROS: usize = 100_000;
+
+/// The cgroup brioche moves itself into, so controllers can be enabled for
+/// the build cgroups next to it (cgroups with processes can't have child
+/// cgroups with controllers enabled).
+const SUPERVISOR_CGROUP_NAME: &str = "brioche-supervisor";
+
+/// Limits on the resources a build can use. Memory, process and CPU limits
+/// are enforced with a cgroup when cgroup v2 is delegated to the current
+/// user (e.g. when run with `systemd-run --user --scope -p Delegate=yes`).
+/// Without a cgroup, memory and CPU limits fall back to weaker limits on
+/// each process, and process limits can't be enforced at all.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ResourceLimits {
+    /// Maximum memory for a build (e.g. `4G`). Without cgroups, this limits
+    /// the address space of each process instead
+    #[clap(long = "memory-limit", value_parser = parse_size)]
+    pub memory_bytes: Option<u64>,
+    /// Maximum number of processes a build can run at once. Needs cgroups,
+    /// since the process rlimit would count every process the current user
+    /// is running on the host
+    #[clap(long)]
+    pub max_processes: Option<u64>,
+    /// Number of CPUs a build can use
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub cpus: Option<usize>,
+    /// Maximum disk space for the files a build writes (e.g. `20G`)
+    #[clap(long = "disk-limit", value_parser = parse_size)]
+    pub disk_bytes: Option<u64>,
+}
+
+impl ResourceLimits {
+    fn cgroup_controllers(&self) -> Vec<&'static str> {
+        let mut controllers = vec![];
+        if self.memory_bytes.is_some() {
+            controllers.push("memory");
+        }
+        if self.max_processes.is_some() {
+            controllers.push("pids");
+        }
+        if self.cpus.is_some() {
+            controllers.push("cpu");
+        }
+        controllers
+    }
+
+    pub fn needs_cgroup(&self) -> bool {
+        !self.cgroup_controllers().is_empty()
+    }
+}
+
+/// A build failed because it went over one of its resource limits.
+#[derive(Debug)]
+pub enum LimitExceeded {
+    Memory { limit_bytes: u64 },
+    Processes { limit: u64 },
+    Disk { limit_bytes: u64 },
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Memory { limit_bytes } => write!(
+                f,
+                "build ran out of memory (limited to {})",
+                format_size(*limit_bytes)
+            ),
+            Self::Processes { limit } => write!(
+                f,
+                "build tried to run more than {} processes at once",
+                limit
+            ),
+            Self::Disk { limit_bytes } => write!(
+                f,
+                "build wrote more than its disk limit of {}",
+                format_size(*limit_bytes)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// A cgroup for a single build. Everything left in the cgroup gets killed
+/// and the cgroup gets removed when it's dropped.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    limits: ResourceLimits,
+    events: Arc<dyn EventSink>,
+}
+
+impl Cgroup {
+    /// Create a cgroup with the given limits next to the cgroup brioche is
+    /// running in. Fails if cgroup v2 isn't delegated to the current user.
+    pub fn create(limits: &ResourceLimits, events: Arc<dyn EventSink>) -> anyhow::Result<Self> {
+        let parent_path = prepare_parent_cgroup()?;
+
+        let controllers = limits.cgroup_controllers();
+        let available_controllers =
+            std::fs::read_to_string(parent_path.join("cgroup.controllers"))?;
+        for controller in &controllers {
+            if !available_controllers
+                .split_whitespace()
+                .any(|available| available == *controller)
+            {
+                anyhow::bail!("cgroup controller {} is not delegated", controller);
+            }
+        }
+        let subtree_control = controllers
+            .iter()
+            .map(|controller| format!("+{}", controller))
+            .join_with(" ")
+            .to_string();
+        std::fs::write(parent_path.join("cgroup.subtree_control"), subtree_control)?;
+
+        let path = parent_path.join(format!("brioche-build-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&path)?;
+        let cgroup = Self {
+            path,
+            limits: limits.clone(),
+            events,
+        };
+
+        if let Some(memory_bytes) = limits.memory_bytes {
+            std::fs::write(cgroup.path.join("memory.max"), memory_bytes.to_string())?;
+
+            // Swapping would let the build go over the limit without being
+            // stopped (and not every kernel has swap accounting)
+            let _ = std::fs::write(cgroup.path.join("memory.swap.max"), "0");
+        }
+        if let Some(max_processes) = limits.max_processes {
+            std::fs::write(cgroup.path.join("pids.max"), max_processes.to_string())?;
+        }
+        if let Some(cpus) = limits.cpus {
+            std::fs::write(
+                cgroup.path.join("cpu.max"),
+                format!("{} {}", cpus * CPU_PERIOD_MICROS, CPU_PERIOD_MICROS),
+            )?;
+        }
+
+        Ok(cgroup)
+    }
+
+    /// The file to write a PID to in order to move it into the cgroup.
+    pub fn procs_path(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+
+    /// The most memory used by everything in the cgroup at once. Only
+    /// tracked with a memory limit (and on Linux 5.19 or later).
+    pub fn peak_memory_bytes(&self) -> Option<u64> {
+        let peak_memory = std::fs::read_to_string(self.path.join("memory.peak")).ok()?;
+        peak_memory.trim().parse().ok()
+    }
+
+    /// Check the cgroup's events for a limit that was hit.
+    pub fn check_limits(&self) -> anyhow::Result<()> {
+        if let Some(limit_bytes) = self.limits.memory_bytes {
+            if read_event_count(&self.path.join("memory.events"), "oom_kill")? > 0 {
+                return Err(LimitExceeded::Memory { limit_bytes }.into());
+            }
+        }
+        if let Some(limit) = self.limits.max_processes {
+            if read_event_count(&self.path.join("pids.events"), "max")? > 0 {
+                return Err(LimitExceeded::Processes { limit }.into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // Processes take a moment to exit after being killed, and the cgroup
+        // can only be removed once it's empty
+        let _ = std::fs::write(self.path.join("cgroup.kill"), "1");
+        for _ in 0..50 {
+            match std::fs::remove_dir(&self.path) {
+                Ok(()) => {
+                    return;
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                    return;
+                }
+                Err(_) => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+
+        self.events.emit(Event::Message {
+            level: MessageLevel::Warning,
+            message: format!("failed to remove cgroup {}", self.path.display()),
+            details: vec![],
+        });
+    }
+}
+
+/// Get the cgroup to create build cgroups in, moving brioche into its own
+/// leaf cgroup the first time.
+fn prepare_parent_cgroup() -> anyhow::Result<PathBuf> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+    let current_path = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| anyhow::anyhow!("cgroup v2 is not available"))?;
+
+    let cgroup_root = Path::new("/sys/fs/cgroup");
+    if !cgroup_root.join("cgroup.controllers").exists() {
+        anyhow::bail!("cgroup v2 is not mounted at {}", cgroup_root.display());
+    }
+    let current_dir = cgroup_root.join(current_path.trim_start_matches('/'));
+
+    if current_dir.file_name() == Some(SUPERVISOR_CGROUP_NAME.as_ref()) {
+        let parent_dir = current_dir
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("invalid cgroup path {}", current_dir.display()))?;
+        return Ok(parent_dir.to_owned());
+    }
+
+    let supervisor_dir = current_dir.join(SUPERVISOR_CGROUP_NAME);
+    match std::fs::create_dir(&supervisor_dir) {
+        Ok(()) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(error) => {
+            return Err(error.into());
+        }
+    }
+    std::fs::write(
+        supervisor_dir.join("cgroup.procs"),
+        std::process::id().to_string(),
+    )?;
+
+    Ok(current_dir)
+}
+
+fn read_event_count(events_path: &Path, event: &str) -> anyhow::Result<u64> {
+    let events = std::fs::read_to_string(events_path)?;
+    let count = events
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .find(|(name, _)| *name == event)
+        .map(|(_, count)| count.trim().parse())
+        .transpose()?;
+    Ok(count.unwrap_or(0))
+}
+
+/// Apply the memory limit to the current process with an rlimit, for when
+/// there's no cgroup. This gets inherited by the build's processes, but is
+/// weaker than the cgroup limit: it applies to the address space of each
+/// process separately, and going over it shows up as failed allocations
+/// rather than as a distinct error (see `rlimit_failure_note`).
+pub fn apply_memory_rlimit(limits: &ResourceLimits) -> nix::Result<()> {
+    use nix::sys::resource::{setrlimit, Resource};
+
+    if let Some(memory_bytes) = limits.memory_bytes {
+        setrlimit(Resource::RLIMIT_AS, memory_bytes, memory_bytes)?;
+    }
+
+    Ok(())
+}
+
+/// Stop any single file from growing past the disk limit, since the disk
+/// usage of a build is only checked periodically. Writing past the limit
+/// sends `SIGXFSZ` (see `check_exit_signal`).
+pub fn apply_file_size_rlimit(limits: &ResourceLimits) -> nix::Result<()> {
+    use nix::sys::resource::{setrlimit, Resource};
+
+    if let Some(disk_bytes) = limits.disk_bytes {
+        setrlimit(Resource::RLIMIT_FSIZE, disk_bytes, disk_bytes)?;
+    }
+
+    Ok(())
+}
+
+/// Check if a build was killed for writing a file bigger than its disk
+/// limit.
+pub fn check_exit_signal(limits: &ResourceLimits, signal: i32) -> Result<(), LimitExceeded> {
+    match limits.disk_bytes {
+        Some(limit_bytes) if signal == nix::libc::SIGXFSZ => {
+            Err(LimitExceeded::Disk { limit_bytes })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A note to add to the error of a failed build whose memory limit was
+/// enforced with an rlimit, since going over it can't be told apart from
+/// other failures.
+pub fn rlimit_failure_note(limits: &ResourceLimits) -> Option<String> {
+    let memory_bytes = limits.memory_bytes?;
+    Some(format!(
+        "the build may have gone over its memory limit of {} per process (cgroups aren't available, so this can't be detected)",
+        format_size(memory_bytes)
+    ))
+}
+
+/// Restrict the current process to the first `cpus` CPUs it's allowed to
+/// run on. This is applied even with a cgroup CPU limit, so tools like
+/// `nproc` report the number of CPUs the build can actually use.
+pub fn restrict_cpus(cpus: usize) -> nix::Result<()> {
+    use nix::sched::{sched_getaffinity, sched_setaffinity, CpuSet};
+
+    let pid = nix::unistd::Pid::from_raw(0);
+    let current_cpus = sched_getaffinity(pid)?;
+
+    let mut restricted_cpus = CpuSet::new();
+    let mut num_cpus = 0;
+    for cpu in 0..CpuSet::count() {
+        if num_cpus == cpus {
+            break;
+        }
+        if current_cpus.is_set(cpu)? {
+            restricted_cpus.set(cpu)?;
+            num_cpus += 1;
+        }
+    }
+
+    sched_setaffinity(pid, &restricted_cpus)
+}
+
+/// Wait until the files in a dir take up more than `limit_bytes` of disk
+/// space, checking periodically. Only files written after this starts count
+/// towards the limit, so files already in the dir (like a root filesystem
+/// copied into it) aren't counted.
+pub async fn wait_for_disk_limit(dir: &Path, limit_bytes: u64) -> anyhow::Result<LimitExceeded> {
+    let initial_usage = disk_usage(dir).await?;
+
+    loop {
+        let scan_start = std::time::Instant::now();
+        let usage = disk_usage(dir).await?;
+        if usage.saturating_sub(initial_usage) > limit_bytes {
+            return Ok(LimitExceeded::Disk { limit_bytes });
+        }
+
+        let interval = DISK_USAGE_INTERVAL.max(scan_start.elapsed());
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn disk_usage(dir: &Path) -> anyhow::Result<u64> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    let dir = dir.to_owned();
+    let usage = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let mut usage = 0;
+        let mut pending_dirs = vec![dir];
+        while let Some(dir) = pending_dirs.pop() {
+            // The build is still running, so files can disappear while
+            // they're being counted. Builds can also make dirs that can't be
+            // read (which just go uncounted)
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(error) if is_skippable_error(&error) => {
+                    continue;
+                }
+                Err(error) => {
+                    return Err(error.into());
+                }
+            };
+
+            for entry in entries {
+                let entry = match entry {
+                    Ok(entry) => entry,
+                    Err(error) if is_skippable_error(&error) => {
+                        continue;
+                    }
+                    Err(error) => {
+                        return Err(error.into());
+                    }
+                };
+                let metadata = match entry.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(error) if is_skippable_error(&error) => {
+                        continue;
+                    }
+                    Err(error) => {
+                        return Err(error.into());
+                    }
+                };
+
+                // Blocks are always 512 bytes, regardless of the filesystem
+                usage += metadata.blocks() * 512;
+                if metadata.is_dir() {
+                    pending_dirs.push(entry.path());
+                }
+            }
+        }
+
+        Ok(usage)
+    })
+    .await??;
+
+    Ok(usage)
+}
+
+fn is_skippable_error(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied
+    )
+}
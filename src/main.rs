@@ -1,20 +1,178 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use anyhow::Context as _;
 use clap::Parser as _;
 
 mod bake;
 mod bootstrap_env;
+mod check;
+mod conflicts;
+mod doctor;
+mod env;
+mod event;
+mod format;
+mod gc;
+mod graph;
 mod hash;
+mod id_map;
+mod limits;
+mod mount_backend;
+mod output;
+mod profile;
+mod progress;
 mod recipe;
+mod relocate;
+mod run;
+mod sandbox;
+mod show;
 mod state;
+mod verify;
 
 #[derive(Debug, clap::Parser)]
-enum Args {
+struct Args {
+    /// How to report progress while resolving and baking recipes
+    #[clap(long, value_enum, global = true, default_value = "text")]
+    output: event::OutputFormat,
+    /// How isolated build sandboxes are from the host
+    #[clap(long, value_enum, global = true, default_value = "hardened")]
+    sandbox_profile: sandbox::SandboxProfile,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
     Build {
         #[clap(long)]
         repo: PathBuf,
+        #[clap(long)]
+        keep_failed: bool,
+        #[clap(long)]
+        debug_on_failure: bool,
+        /// Kill build scripts that run for longer than this many seconds
+        /// (unless the recipe sets its own timeout)
+        #[clap(long)]
+        timeout: Option<u64>,
+        #[clap(flatten)]
+        limits: limits::ResourceLimits,
+        recipe: String,
+    },
+    Shell {
+        #[clap(long)]
+        repo: PathBuf,
+        /// Kill build scripts of dependencies that run for longer than this
+        /// many seconds (unless the recipe sets its own timeout)
+        #[clap(long)]
+        timeout: Option<u64>,
+        recipe: String,
+    },
+    Run {
+        #[clap(long)]
+        repo: PathBuf,
+        /// The binary to run from the recipe (defaults to the recipe name)
+        #[clap(long)]
+        bin: Option<String>,
+        /// Run the binary inside the bootstrap sandbox instead of on the host
+        #[clap(long)]
+        sandbox: bool,
+        /// Kill build scripts that run for longer than this many seconds
+        /// (unless the recipe sets its own timeout)
+        #[clap(long)]
+        timeout: Option<u64>,
+        recipe: String,
+        #[clap(last = true)]
+        args: Vec<std::ffi::OsString>,
+    },
+    Env {
+        #[clap(long)]
+        repo: PathBuf,
+        /// The project dir to work in (defaults to the current dir)
+        #[clap(long)]
+        source: Option<PathBuf>,
+        /// Start the shell on the host instead of inside the bootstrap sandbox
+        #[clap(long)]
+        host: bool,
+        /// Kill build scripts of dependencies that run for longer than this
+        /// many seconds (unless the recipe sets its own timeout)
+        #[clap(long)]
+        timeout: Option<u64>,
+        recipe: String,
+    },
+    Check {
+        #[clap(long)]
+        repo: PathBuf,
+        #[clap(long)]
+        keep_failed: bool,
+        /// Kill build scripts that run for longer than this many seconds
+        /// (unless the recipe sets its own timeout)
+        #[clap(long)]
+        timeout: Option<u64>,
+        #[clap(flatten)]
+        limits: limits::ResourceLimits,
+        recipe: String,
+    },
+    Show {
+        #[clap(long)]
+        repo: Option<PathBuf>,
+        #[clap(long)]
+        json: bool,
+        /// A recipe hash, or a recipe name to resolve from `--repo`
+        recipe: String,
+    },
+    Graph {
+        #[clap(long)]
+        repo: PathBuf,
+        #[clap(long, value_enum, default_value = "text")]
+        format: graph::GraphFormat,
+        recipe: String,
+    },
+    Why {
+        #[clap(long)]
+        repo: PathBuf,
+        recipe: String,
+        /// The name or hash of a recipe in the dependency graph
+        dependency: String,
+    },
+    Verify {
+        /// Recipe hashes to verify (defaults to every baked recipe)
+        recipe_refs: Vec<recipe::ResolvedRecipeRef>,
+    },
+    Gc {
+        #[clap(long)]
+        dry_run: bool,
+        #[clap(long, default_value = "7")]
+        keep_days: u64,
+    },
+    Install {
+        #[clap(long)]
+        repo: PathBuf,
+        /// Kill build scripts that run for longer than this many seconds
+        /// (unless the recipe sets its own timeout)
+        #[clap(long)]
+        timeout: Option<u64>,
+        #[clap(flatten)]
+        limits: limits::ResourceLimits,
         recipe: String,
     },
+    Uninstall {
+        /// The name of the installed recipe
+        recipe: String,
+    },
+    Rollback {
+        /// The generation to switch to (defaults to the previous generation)
+        generation: Option<u32>,
+    },
+    Pin {
+        recipe_ref: recipe::ResolvedRecipeRef,
+    },
+    Unpin {
+        recipe_ref: recipe::ResolvedRecipeRef,
+    },
+    /// Check which sandboxing features the host supports
+    Doctor,
 }
 
 #[tokio::main]
@@ -24,39 +182,317 @@ async fn main() {
         Ok(()) => {}
         Err(error) => {
             eprintln!("{:#}", error);
-            std::process::exit(1);
+            match error.downcast_ref::<Cancelled>() {
+                Some(Cancelled(signal)) => {
+                    std::process::exit(128 + *signal as i32);
+                }
+                None => {
+                    std::process::exit(1);
+                }
+            }
         }
     }
 }
 
 async fn run() -> anyhow::Result<()> {
-    let opt = Args::parse();
+    let args = Args::parse();
 
-    let Args::Build { repo, recipe } = opt;
+    let events: Arc<dyn event::EventSink> = match args.output {
+        event::OutputFormat::Text if progress::is_terminal() => {
+            Arc::new(progress::ProgressEventSink::new())
+        }
+        event::OutputFormat::Text | event::OutputFormat::Plain => Arc::new(event::TextEventSink),
+        event::OutputFormat::Json => Arc::new(event::JsonEventSink),
+    };
+    let state = state::State::new(events).await?;
+    let sandbox_profile = args.sandbox_profile;
 
-    let state = state::State::new().await?;
+    match args.command {
+        Command::Build {
+            repo,
+            keep_failed,
+            debug_on_failure,
+            timeout,
+            limits,
+            recipe,
+        } => {
+            let options = bake::BakeOptions {
+                keep_failed,
+                debug_on_failure,
+                timeout: timeout.map(std::time::Duration::from_secs),
+                limits,
+                sandbox_profile,
+            };
+            until_cancelled(build(&state, &repo, &recipe, &options)).await?;
+        }
+        Command::Shell {
+            repo,
+            timeout,
+            recipe,
+        } => {
+            let options = bake::BakeOptions {
+                timeout: timeout.map(std::time::Duration::from_secs),
+                sandbox_profile,
+                ..Default::default()
+            };
 
-    let mut recipe_set = recipe::ResolvedRecipeSet::new();
-    let resolved_recipe = recipe::resolve_recipe(&state, &*repo, &recipe, &mut recipe_set).await?;
-    let baked_recipe = bake::get_baked_recipe(&state, &recipe_set, &resolved_recipe).await?;
+            until_cancelled(async {
+                let mut recipe_set = recipe::ResolvedRecipeSet::new();
+                let resolved_recipe =
+                    recipe::resolve_recipe(&state, &repo, &recipe, &mut recipe_set).await?;
+                bake::shell(&state, &recipe_set, &resolved_recipe, &options).await
+            })
+            .await?;
+        }
+        Command::Run {
+            repo,
+            bin,
+            sandbox,
+            timeout,
+            recipe,
+            args,
+        } => {
+            let bake_options = bake::BakeOptions {
+                timeout: timeout.map(std::time::Duration::from_secs),
+                sandbox_profile,
+                ..Default::default()
+            };
+            let options = run::RunOptions { bin, args, sandbox };
 
-    let recipe = recipe_set.get(&resolved_recipe);
+            let exit_code = until_cancelled(async {
+                let mut recipe_set = recipe::ResolvedRecipeSet::new();
+                let resolved_recipe =
+                    recipe::resolve_recipe(&state, &repo, &recipe, &mut recipe_set).await?;
+                run::run(
+                    &state,
+                    &recipe_set,
+                    &resolved_recipe,
+                    &bake_options,
+                    &options,
+                )
+                .await
+            })
+            .await?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+        }
+        Command::Env {
+            repo,
+            source,
+            host,
+            timeout,
+            recipe,
+        } => {
+            let source_dir = match source {
+                Some(source) => tokio::fs::canonicalize(&source)
+                    .await
+                    .with_context(|| format!("source dir {} not found", source.display()))?,
+                None => std::env::current_dir()?,
+            };
+            let bake_options = bake::BakeOptions {
+                timeout: timeout.map(std::time::Duration::from_secs),
+                sandbox_profile,
+                ..Default::default()
+            };
+            let options = env::EnvOptions { source_dir, host };
+
+            let exit_code = until_cancelled(async {
+                let mut recipe_set = recipe::ResolvedRecipeSet::new();
+                let resolved_recipe =
+                    recipe::resolve_recipe(&state, &repo, &recipe, &mut recipe_set).await?;
+                env::env(
+                    &state,
+                    &recipe_set,
+                    &resolved_recipe,
+                    &bake_options,
+                    &options,
+                )
+                .await
+            })
+            .await?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+        }
+        Command::Check {
+            repo,
+            keep_failed,
+            timeout,
+            limits,
+            recipe,
+        } => {
+            let options = bake::BakeOptions {
+                keep_failed,
+                timeout: timeout.map(std::time::Duration::from_secs),
+                limits,
+                sandbox_profile,
+                ..Default::default()
+            };
+
+            until_cancelled(async {
+                let mut recipe_set = recipe::ResolvedRecipeSet::new();
+                let resolved_recipe =
+                    recipe::resolve_recipe(&state, &repo, &recipe, &mut recipe_set).await?;
+                check::check(&state, &recipe_set, &resolved_recipe, &options).await
+            })
+            .await?;
+        }
+        Command::Show { repo, json, recipe } => match recipe.parse::<recipe::ResolvedRecipeRef>() {
+            Ok(recipe_ref) => {
+                show::show(&state, &recipe_ref, None, json).await?;
+            }
+            Err(_) => {
+                let repo = repo.context("--repo is required to show a recipe by name")?;
 
-    println!(
-        "Built {} {} to {}",
-        recipe.name,
-        recipe.version,
-        baked_recipe.prefix_path.display()
-    );
+                let mut recipe_set = recipe::ResolvedRecipeSet::new();
+                let recipe_ref =
+                    recipe::resolve_recipe(&state, &repo, &recipe, &mut recipe_set).await?;
+                let resolved_recipe = recipe_set.get(&recipe_ref);
+                show::show(&state, &recipe_ref, Some(resolved_recipe), json).await?;
+            }
+        },
+        Command::Graph {
+            repo,
+            format,
+            recipe,
+        } => {
+            let mut recipe_set = recipe::ResolvedRecipeSet::new();
+            let resolved_recipe =
+                recipe::resolve_recipe(&state, &repo, &recipe, &mut recipe_set).await?;
+            graph::graph(&state, &recipe_set, &resolved_recipe, format).await?;
+        }
+        Command::Why {
+            repo,
+            recipe,
+            dependency,
+        } => {
+            let mut recipe_set = recipe::ResolvedRecipeSet::new();
+            let resolved_recipe =
+                recipe::resolve_recipe(&state, &repo, &recipe, &mut recipe_set).await?;
+            graph::why(&recipe_set, &resolved_recipe, &dependency)?;
+        }
+        Command::Verify { recipe_refs } => {
+            verify::verify(&state, &recipe_refs).await?;
+        }
+        Command::Gc { dry_run, keep_days } => {
+            let options = gc::GcOptions {
+                dry_run,
+                keep_recent: std::time::Duration::from_secs(keep_days * 24 * 60 * 60),
+            };
+            gc::collect_garbage(&state, &options).await?;
+        }
+        Command::Install {
+            repo,
+            timeout,
+            limits,
+            recipe,
+        } => {
+            let options = bake::BakeOptions {
+                timeout: timeout.map(std::time::Duration::from_secs),
+                limits,
+                sandbox_profile,
+                ..Default::default()
+            };
 
-    match state.persist_lockfile().await? {
-        true => {
-            println!("Updated lockfile");
+            until_cancelled(async {
+                let mut recipe_set = recipe::ResolvedRecipeSet::new();
+                let resolved_recipe =
+                    recipe::resolve_recipe(&state, &repo, &recipe, &mut recipe_set).await?;
+                profile::install(&state, &recipe_set, &resolved_recipe, &options).await
+            })
+            .await?;
+        }
+        Command::Uninstall { recipe } => {
+            profile::uninstall(&state, &recipe).await?;
+        }
+        Command::Rollback { generation } => {
+            profile::rollback(&state, generation).await?;
         }
-        false => {
-            println!("Lockfile already up to date");
+        Command::Pin { recipe_ref } => {
+            gc::pin(&state, &recipe_ref).await?;
+            println!("Pinned recipe {}", recipe_ref);
+        }
+        Command::Unpin { recipe_ref } => {
+            gc::unpin(&state, &recipe_ref).await?;
+            println!("Unpinned recipe {}", recipe_ref);
+        }
+        Command::Doctor => {
+            doctor::doctor(&state).await?;
         }
     }
 
     Ok(())
 }
+
+async fn build(
+    state: &state::State,
+    repo: &Path,
+    recipe: &str,
+    options: &bake::BakeOptions,
+) -> anyhow::Result<()> {
+    let mut recipe_set = recipe::ResolvedRecipeSet::new();
+    let resolved_recipe = recipe::resolve_recipe(state, repo, recipe, &mut recipe_set).await?;
+    let baked_recipe =
+        bake::get_baked_recipe(state, &recipe_set, &resolved_recipe, options).await?;
+
+    let recipe = recipe_set.get(&resolved_recipe);
+
+    state.emit(event::Event::RecipeReady {
+        recipe_ref: resolved_recipe,
+        name: recipe.name.clone(),
+        version: recipe.version.clone(),
+        prefix_path: baked_recipe.prefix_path,
+    });
+
+    let updated = state.persist_lockfile().await?;
+    state.emit(event::Event::LockfileUpdated { updated });
+
+    Ok(())
+}
+
+/// Returned when brioche is stopped by a signal (which gets used for the
+/// exit code).
+#[derive(Debug)]
+struct Cancelled(nix::sys::signal::Signal);
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cancelled by {}", self.0)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Run a future until it finishes or brioche receives SIGINT or SIGTERM.
+/// When cancelled, the future gets dropped, which kills any running build
+/// sandbox and removes its work dir. The lockfile is only ever replaced
+/// atomically, so it's left as it was last persisted.
+async fn until_cancelled<T>(
+    future: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    use nix::sys::signal::Signal;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::pin!(future);
+
+    loop {
+        tokio::select! {
+            result = &mut future => {
+                return result;
+            }
+            _ = sigint.recv() => {
+                // Ctrl-C goes to an interactive child (like a shell) instead
+                if !bootstrap_env::has_interactive_child() {
+                    return Err(Cancelled(Signal::SIGINT).into());
+                }
+            }
+            _ = sigterm.recv() => {
+                return Err(Cancelled(Signal::SIGTERM).into());
+            }
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    ffi::CString,
+    os::unix::ffi::OsStrExt as _,
+    path::Path,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::state::State;
+
+/// The backend picked by `detect`, so probing only happens once per run (0
+/// if it hasn't been detected yet).
+static DETECTED_BACKEND: AtomicU8 = AtomicU8::new(0);
+
+/// Exit code of the overlayfs probe if it couldn't enter a user namespace.
+const PROBE_NAMESPACE_FAILED: i32 = 255;
+
+/// How the layers of a bootstrap environment get combined into the root
+/// the build runs in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountBackend {
+    /// The kernel's overlayfs, mounted from within the sandbox's user
+    /// namespace (needs Linux 5.11 or later)
+    KernelOverlayfs,
+    /// Overlayfs with the `fuse-overlayfs` binary from the host
+    FuseOverlayfs,
+    /// Copy every layer into the upper dir before the build starts. Slow,
+    /// but it works anywhere
+    Copy,
+}
+
+impl MountBackend {
+    /// Every backend, in order of preference.
+    pub const ALL: [Self; 3] = [Self::KernelOverlayfs, Self::FuseOverlayfs, Self::Copy];
+
+    fn to_id(self) -> u8 {
+        match self {
+            Self::KernelOverlayfs => 1,
+            Self::FuseOverlayfs => 2,
+            Self::Copy => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|backend| backend.to_id() == id)
+    }
+}
+
+impl std::fmt::Display for MountBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::KernelOverlayfs => write!(f, "kernel overlayfs"),
+            Self::FuseOverlayfs => write!(f, "fuse-overlayfs"),
+            Self::Copy => write!(f, "copy"),
+        }
+    }
+}
+
+/// Whether a backend can be used on this host, and why not if it can't.
+#[derive(Debug)]
+pub struct BackendSupport {
+    pub backend: MountBackend,
+    pub support: anyhow::Result<()>,
+}
+
+/// Get the most preferred backend that's supported on this host. The
+/// result is reused for the rest of the run.
+pub async fn detect(state: &State) -> anyhow::Result<MountBackend> {
+    if let Some(backend) = MountBackend::from_id(DETECTED_BACKEND.load(Ordering::SeqCst)) {
+        return Ok(backend);
+    }
+
+    let backend = probe_backends(state)
+        .await?
+        .into_iter()
+        .find(|backend_support| backend_support.support.is_ok())
+        .map(|backend_support| backend_support.backend)
+        .unwrap_or(MountBackend::Copy);
+    DETECTED_BACKEND.store(backend.to_id(), Ordering::SeqCst);
+
+    Ok(backend)
+}
+
+/// Check which backends are supported on this host, in order of preference.
+pub async fn probe_backends(state: &State) -> anyhow::Result<Vec<BackendSupport>> {
+    let probe_work_dir = state.new_temp_work_dir().await?;
+    let probe_dir = probe_work_dir.path.clone();
+
+    let kernel_overlayfs_support = {
+        let probe_dir = probe_dir.clone();
+        tokio::task::spawn_blocking(move || probe_kernel_overlayfs(&probe_dir)).await?
+    };
+
+    let _ = tokio::fs::remove_dir_all(&probe_dir).await;
+    if let Some(parent_dir) = probe_dir.parent() {
+        let _ = tokio::fs::remove_dir(parent_dir).await;
+    }
+
+    Ok(vec![
+        BackendSupport {
+            backend: MountBackend::KernelOverlayfs,
+            support: kernel_overlayfs_support,
+        },
+        BackendSupport {
+            backend: MountBackend::FuseOverlayfs,
+            support: probe_fuse_overlayfs(),
+        },
+        BackendSupport {
+            backend: MountBackend::Copy,
+            support: Ok(()),
+        },
+    ])
+}
+
+/// Try mounting overlayfs from a new user namespace, the same way the
+/// sandbox would.
+fn probe_kernel_overlayfs(probe_dir: &Path) -> anyhow::Result<()> {
+    use nix::{
+        sched::CloneFlags,
+        sys::wait::{waitpid, WaitStatus},
+        unistd::ForkResult,
+    };
+
+    let filesystems = std::fs::read_to_string("/proc/filesystems")?;
+    let has_overlayfs = filesystems
+        .lines()
+        .any(|line| line.split_whitespace().last() == Some("overlay"));
+    if !has_overlayfs {
+        anyhow::bail!("the kernel doesn't support overlayfs");
+    }
+
+    let lower_dir = probe_dir.join("lower");
+    let upper_dir = probe_dir.join("upper");
+    let work_dir = probe_dir.join("work");
+    let target_dir = probe_dir.join("target");
+    for dir in [&lower_dir, &upper_dir, &work_dir, &target_dir] {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    // Everything the child needs is allocated up front, since allocating
+    // after forking isn't safe (nix copies short paths onto the stack)
+    let target_dir = CString::new(target_dir.as_os_str().as_bytes())?;
+    let mount_options = CString::new(overlayfs_mount_options(
+        &[lower_dir.as_path()],
+        &upper_dir,
+        &work_dir,
+    ))?;
+    let uid_map = format!("0 {} 1", nix::unistd::getuid());
+    let gid_map = format!("0 {} 1", nix::unistd::getgid());
+
+    // SAFETY: The child only makes syscalls before exiting
+    let fork_result = unsafe { nix::unistd::fork()? };
+    let child_pid = match fork_result {
+        ForkResult::Child => {
+            let result = nix::sched::unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)
+                .and_then(|()| write_proc_file("/proc/self/setgroups", b"deny"))
+                .and_then(|()| write_proc_file("/proc/self/uid_map", uid_map.as_bytes()))
+                .and_then(|()| write_proc_file("/proc/self/gid_map", gid_map.as_bytes()));
+            if result.is_err() {
+                // SAFETY: `_exit` is always safe to call
+                unsafe { nix::libc::_exit(PROBE_NAMESPACE_FAILED) };
+            }
+
+            let result = nix::mount::mount(
+                Some("overlay"),
+                target_dir.as_c_str(),
+                Some("overlay"),
+                nix::mount::MsFlags::empty(),
+                Some(mount_options.as_c_str()),
+            );
+            let exit_code = match result {
+                Ok(()) => 0,
+                Err(errno) => errno as i32,
+            };
+
+            // SAFETY: `_exit` is always safe to call
+            unsafe { nix::libc::_exit(exit_code) };
+        }
+        ForkResult::Parent { child } => child,
+    };
+
+    match waitpid(child_pid, None)? {
+        WaitStatus::Exited(_, 0) => Ok(()),
+        WaitStatus::Exited(_, PROBE_NAMESPACE_FAILED) => {
+            anyhow::bail!("unprivileged user namespaces aren't available");
+        }
+        WaitStatus::Exited(_, errno) => {
+            anyhow::bail!(
+                "mounting overlayfs in a user namespace failed: {}",
+                nix::errno::Errno::from_i32(errno)
+            );
+        }
+        status => {
+            anyhow::bail!("overlayfs probe exited unexpectedly: {:?}", status);
+        }
+    }
+}
+
+fn write_proc_file(path: &str, content: &[u8]) -> nix::Result<()> {
+    use nix::{fcntl::OFlag, sys::stat::Mode};
+
+    let fd = nix::fcntl::open(path, OFlag::O_WRONLY, Mode::empty())?;
+    let result = nix::unistd::write(fd, content);
+    let _ = nix::unistd::close(fd);
+    result?;
+
+    Ok(())
+}
+
+/// The backend to use when kernel overlayfs can't be used for a particular
+/// build.
+pub fn fallback_backend() -> MountBackend {
+    match probe_fuse_overlayfs() {
+        Ok(()) => MountBackend::FuseOverlayfs,
+        Err(_) => MountBackend::Copy,
+    }
+}
+
+fn probe_fuse_overlayfs() -> anyhow::Result<()> {
+    if which::which("fuse-overlayfs").is_err() {
+        anyhow::bail!("fuse-overlayfs is not installed");
+    }
+    if which::which("fusermount").is_err() {
+        anyhow::bail!("fusermount is not installed");
+    }
+    if !Path::new("/dev/fuse").exists() {
+        anyhow::bail!("/dev/fuse is not available");
+    }
+
+    Ok(())
+}
+
+/// The options for mounting overlayfs, with the topmost lower dir first.
+/// `userxattr` is needed to mount overlayfs in a user namespace.
+pub fn overlayfs_mount_options(lower_dirs: &[&Path], upper_dir: &Path, work_dir: &Path) -> String {
+    use joinery::JoinableIterator as _;
+
+    let lower_dirs = lower_dirs.iter().map(|dir| dir.display()).join_with(":");
+    format!(
+        "lowerdir={},upperdir={},workdir={},userxattr",
+        lower_dirs,
+        upper_dir.display(),
+        work_dir.display()
+    )
+}
+
+/// Check if overlayfs mount options fit in the page of mount data the
+/// kernel accepts.
+pub fn fits_mount_data(mount_options: &str) -> bool {
+    let page_size = match nix::unistd::sysconf(nix::unistd::SysconfVar::PAGE_SIZE) {
+        Ok(Some(page_size)) => page_size as usize,
+        _ => 4096,
+    };
+
+    // The options are passed as a null-terminated string
+    mount_options.len() < page_size
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{fits_mount_data, overlayfs_mount_options};
+
+    #[test]
+    fn test_overlayfs_mount_options() {
+        let options = overlayfs_mount_options(
+            &[Path::new("/layers/1"), Path::new("/layers/0")],
+            Path::new("/upper"),
+            Path::new("/work"),
+        );
+        assert_eq!(
+            options,
+            "lowerdir=/layers/1:/layers/0,upperdir=/upper,workdir=/work,userxattr"
+        );
+    }
+
+    #[test]
+    fn test_fits_mount_data() {
+        let few_layers: Vec<_> = (0..3)
+            .map(|index| PathBuf::from(format!("/work/layers/dependencies/{}", index)))
+            .collect();
+        let few_layers: Vec<_> = few_layers.iter().map(|dir| dir.as_path()).collect();
+        let options = overlayfs_mount_options(&few_layers, Path::new("/upper"), Path::new("/work"));
+        assert!(fits_mount_data(&options));
+
+        let many_layers: Vec<_> = (0..1000)
+            .map(|index| PathBuf::from(format!("/work/layers/dependencies/{}", index)))
+            .collect();
+        let many_layers: Vec<_> = many_layers.iter().map(|dir| dir.as_path()).collect();
+        let options =
+            overlayfs_mount_options(&many_layers, Path::new("/upper"), Path::new("/work"));
+        assert!(!fits_mount_data(&options));
+    }
+}
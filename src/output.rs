@@ -0,0 +1,480 @@
+use std::path::{Path, PathBuf};
+
+use crate::mount_backend::MountBackend;
+
+/// Prefix used by fuse-overlayfs for whiteout files (and opaque dir
+/// markers) when it can't create whiteout device nodes.
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Prefix of the xattrs kernel overlayfs uses to keep track of opaque dirs
+/// (among other things) in the upper dir when mounted with `userxattr`.
+const OVERLAY_XATTR_PREFIX: &[u8] = b"user.overlay.";
+
+/// Remove overlayfs whiteouts and dependency files that were only copied up
+/// into a recipe's output prefix, returning the ones the build changed.
+/// `dependency_prefixes` start with the one that takes precedence.
+pub async fn exclude_dependency_files(
+    output_prefix: &Path,
+    dependency_prefixes: &[&Path],
+    mount_backend: MountBackend,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let output_prefix = output_prefix.to_owned();
+    let dependency_prefixes: Vec<_> = dependency_prefixes
+        .iter()
+        .map(|prefix| prefix.to_path_buf())
+        .collect();
+
+    let overridden_paths = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let mut overridden_paths = vec![];
+        if output_prefix.is_dir() {
+            remove_overlay_xattrs(&output_prefix)?;
+            exclude_dir_entries(
+                &output_prefix,
+                Path::new(""),
+                &dependency_prefixes,
+                mount_backend,
+                &mut overridden_paths,
+            )?;
+        }
+
+        Ok(overridden_paths)
+    })
+    .await??;
+
+    Ok(overridden_paths)
+}
+
+fn exclude_dir_entries(
+    output_prefix: &Path,
+    relative_dir: &Path,
+    dependency_prefixes: &[PathBuf],
+    mount_backend: MountBackend,
+    overridden_paths: &mut Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    use std::os::unix::fs::{FileTypeExt as _, MetadataExt as _};
+
+    for entry in std::fs::read_dir(output_prefix.join(relative_dir))? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        let relative_path = relative_dir.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        let file_type = metadata.file_type();
+
+        let is_whiteout_device = file_type.is_char_device() && metadata.rdev() == 0;
+        let is_whiteout = match mount_backend {
+            MountBackend::KernelOverlayfs => is_whiteout_device,
+            MountBackend::FuseOverlayfs => {
+                is_whiteout_device
+                    || entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(WHITEOUT_PREFIX)
+            }
+            MountBackend::Copy => false,
+        };
+        if is_whiteout {
+            std::fs::remove_file(&entry_path)?;
+            continue;
+        }
+
+        let dependency_paths: Vec<_> = dependency_prefixes
+            .iter()
+            .map(|prefix| prefix.join(&relative_path))
+            .filter(|path| std::fs::symlink_metadata(path).is_ok())
+            .collect();
+
+        if file_type.is_dir() {
+            remove_overlay_xattrs(&entry_path)?;
+            exclude_dir_entries(
+                output_prefix,
+                &relative_path,
+                dependency_prefixes,
+                mount_backend,
+                overridden_paths,
+            )?;
+
+            // Directories copied up from a dependency only to hold other
+            // copied-up files end up empty
+            let is_empty = std::fs::read_dir(&entry_path)?.next().is_none();
+            if is_empty && !dependency_paths.is_empty() {
+                std::fs::remove_dir(&entry_path)?;
+            }
+        } else if let Some(dependency_path) = dependency_paths.first() {
+            // Compare against the dependency file that was visible to the
+            // build, from the layer with the highest precedence
+            match dependency_file_change(&entry_path, dependency_path)? {
+                DependencyFileChange::CopiedUp => {
+                    std::fs::remove_file(&entry_path)?;
+                }
+                DependencyFileChange::Reinstalled => {}
+                DependencyFileChange::Modified => {
+                    overridden_paths.push(relative_path);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the xattrs overlayfs left on a dir in the upper dir, so they don't
+/// end up in the store.
+fn remove_overlay_xattrs(path: &Path) -> anyhow::Result<()> {
+    use nix::libc;
+    use std::os::unix::ffi::OsStrExt as _;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+
+    // SAFETY: With an empty buffer, `llistxattr` only returns the size of
+    // the list
+    let size = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() == Some(libc::ENOTSUP) {
+            return Ok(());
+        }
+        return Err(error.into());
+    }
+
+    let mut names = vec![0u8; size as usize];
+    // SAFETY: `names` is valid for writes of `names.len()` bytes
+    let size = unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr().cast(), names.len()) };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    names.truncate(size as usize);
+
+    for name in names.split(|&byte| byte == 0) {
+        if !name.starts_with(OVERLAY_XATTR_PREFIX) {
+            continue;
+        }
+
+        let name = std::ffi::CString::new(name)?;
+        // SAFETY: Both arguments are valid null-terminated strings
+        let result = unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) };
+        if result < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+
+    Ok(())
+}
+
+/// How a file in the upper dir differs from the dependency file at the same
+/// path.
+#[derive(Debug, PartialEq, Eq)]
+enum DependencyFileChange {
+    /// The file was only copied up (e.g. because its permissions changed).
+    CopiedUp,
+    /// The build wrote the file again, with the same content.
+    Reinstalled,
+    /// The build changed the file's content.
+    Modified,
+}
+
+fn dependency_file_change(
+    path: &Path,
+    dependency_path: &Path,
+) -> anyhow::Result<DependencyFileChange> {
+    use std::os::unix::fs::MetadataExt as _;
+
+    if !is_same_file_content(path, dependency_path)? {
+        return Ok(DependencyFileChange::Modified);
+    }
+
+    // Copying a file up keeps its mtime, so a newer mtime means the build
+    // wrote the file itself
+    let metadata = std::fs::symlink_metadata(path)?;
+    let dependency_metadata = std::fs::symlink_metadata(dependency_path)?;
+    let is_same_mtime = metadata.mtime() == dependency_metadata.mtime()
+        && metadata.mtime_nsec() == dependency_metadata.mtime_nsec();
+    if is_same_mtime {
+        Ok(DependencyFileChange::CopiedUp)
+    } else {
+        Ok(DependencyFileChange::Reinstalled)
+    }
+}
+
+fn is_same_file_content(a: &Path, b: &Path) -> anyhow::Result<bool> {
+    use std::io::Read as _;
+
+    let a_metadata = std::fs::symlink_metadata(a)?;
+    let b_metadata = std::fs::symlink_metadata(b)?;
+
+    if a_metadata.file_type().is_symlink() && b_metadata.file_type().is_symlink() {
+        return Ok(std::fs::read_link(a)? == std::fs::read_link(b)?);
+    }
+
+    if !a_metadata.is_file() || !b_metadata.is_file() || a_metadata.len() != b_metadata.len() {
+        return Ok(false);
+    }
+
+    let mut a_file = std::fs::File::open(a)?;
+    let mut b_file = std::fs::File::open(b)?;
+    let mut a_buffer = vec![0; 64 * 1024];
+    let mut b_buffer = vec![0; 64 * 1024];
+    loop {
+        let length = a_file.read(&mut a_buffer)?;
+        if length == 0 {
+            // Make sure the other file didn't grow in the meantime
+            return Ok(b_file.read(&mut b_buffer[..1])? == 0);
+        }
+
+        b_file.read_exact(&mut b_buffer[..length])?;
+        if a_buffer[..length] != b_buffer[..length] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Get the total size of the files in a recipe's output prefix, along with
+/// the number of files and symlinks it contains.
+pub async fn output_stats(output_prefix: &Path) -> anyhow::Result<(u64, u64)> {
+    let output_prefix = output_prefix.to_owned();
+    let stats = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        let mut size = 0;
+        let mut file_count = 0;
+        let mut pending_dirs = vec![output_prefix];
+        while let Some(dir) = pending_dirs.pop() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    pending_dirs.push(entry.path());
+                } else {
+                    size += metadata.len();
+                    file_count += 1;
+                }
+            }
+        }
+
+        Ok((size, file_count))
+    })
+    .await??;
+
+    Ok(stats)
+}
+
+/// Reset the mtimes, ownership and permissions in a recipe's output prefix,
+/// so they don't depend on when or by whom the recipe was baked.
+pub async fn normalize_output_metadata(output_prefix: &Path) -> anyhow::Result<()> {
+    let output_prefix = output_prefix.to_owned();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+        if output_prefix.is_dir() {
+            normalize_entry_metadata(&output_prefix)?;
+        }
+
+        Ok(())
+    })
+    .await??;
+
+    Ok(())
+}
+
+fn normalize_entry_metadata(path: &Path) -> anyhow::Result<()> {
+    use nix::{
+        sys::{
+            stat::{FchmodatFlags, Mode, UtimensatFlags},
+            time::{TimeSpec, TimeValLike as _},
+        },
+        unistd::FchownatFlags,
+    };
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let metadata = std::fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+
+    if file_type.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            normalize_entry_metadata(&entry?.path())?;
+        }
+    }
+
+    nix::unistd::fchownat(
+        None,
+        path,
+        Some(nix::unistd::Uid::current()),
+        Some(nix::unistd::Gid::current()),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+
+    // Symlinks don't have their own permissions on Linux
+    if !file_type.is_symlink() {
+        let is_executable = file_type.is_dir() || metadata.permissions().mode() & 0o111 != 0;
+        let mode = if is_executable { 0o755 } else { 0o644 };
+        nix::sys::stat::fchmodat(
+            None,
+            path,
+            Mode::from_bits_truncate(mode),
+            FchmodatFlags::FollowSymlink,
+        )?;
+    }
+
+    let mtime = TimeSpec::seconds(crate::bootstrap_env::SOURCE_DATE_EPOCH);
+    nix::sys::stat::utimensat(None, path, &mtime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{
+        dependency_file_change, exclude_dependency_files, is_same_file_content,
+        DependencyFileChange,
+    };
+    use crate::mount_backend::MountBackend;
+
+    fn temp_dir() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("brioche-output-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn set_mtime(path: &Path, secs: i64) {
+        use nix::sys::{
+            stat::UtimensatFlags,
+            time::{TimeSpec, TimeValLike as _},
+        };
+
+        let time = TimeSpec::seconds(secs);
+        nix::sys::stat::utimensat(None, path, &time, &time, UtimensatFlags::NoFollowSymlink)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_is_same_file_content() {
+        let dir = temp_dir();
+        let big_content = vec![7; 200 * 1024];
+        let mut changed_content = big_content.clone();
+        changed_content[150 * 1024] = 8;
+        std::fs::write(dir.join("a"), &big_content).unwrap();
+        std::fs::write(dir.join("b"), &big_content).unwrap();
+        std::fs::write(dir.join("changed"), &changed_content).unwrap();
+        std::fs::write(dir.join("short"), &big_content[..1024]).unwrap();
+
+        assert!(is_same_file_content(&dir.join("a"), &dir.join("b")).unwrap());
+        assert!(!is_same_file_content(&dir.join("a"), &dir.join("changed")).unwrap());
+        assert!(!is_same_file_content(&dir.join("a"), &dir.join("short")).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dependency_file_change() {
+        let dir = temp_dir();
+        std::fs::write(dir.join("dependency"), "content").unwrap();
+        std::fs::write(dir.join("copied"), "content").unwrap();
+        std::fs::write(dir.join("reinstalled"), "content").unwrap();
+        std::fs::write(dir.join("modified"), "changed").unwrap();
+        set_mtime(&dir.join("dependency"), 1000);
+        set_mtime(&dir.join("copied"), 1000);
+        set_mtime(&dir.join("reinstalled"), 2000);
+
+        let dependency_path = dir.join("dependency");
+        assert_eq!(
+            dependency_file_change(&dir.join("copied"), &dependency_path).unwrap(),
+            DependencyFileChange::CopiedUp
+        );
+        assert_eq!(
+            dependency_file_change(&dir.join("reinstalled"), &dependency_path).unwrap(),
+            DependencyFileChange::Reinstalled
+        );
+        assert_eq!(
+            dependency_file_change(&dir.join("modified"), &dependency_path).unwrap(),
+            DependencyFileChange::Modified
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_whiteout_names_only_with_fuse_overlayfs() {
+        let dir = temp_dir();
+        let output_prefix = dir.join("output");
+        let dependency_prefix = dir.join("dependency");
+        std::fs::create_dir_all(&output_prefix).unwrap();
+        std::fs::create_dir_all(&dependency_prefix).unwrap();
+        std::fs::write(output_prefix.join(".wh.file"), "").unwrap();
+
+        exclude_dependency_files(&output_prefix, &[&dependency_prefix], MountBackend::Copy)
+            .await
+            .unwrap();
+        assert!(output_prefix.join(".wh.file").exists());
+
+        exclude_dependency_files(
+            &output_prefix,
+            &[&dependency_prefix],
+            MountBackend::FuseOverlayfs,
+        )
+        .await
+        .unwrap();
+        assert!(!output_prefix.join(".wh.file").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exclude_dependency_files_keeps_changed_files() {
+        let dir = temp_dir();
+        let output_prefix = dir.join("output");
+        let dependency_prefix = dir.join("dependency");
+        std::fs::create_dir_all(output_prefix.join("share/info")).unwrap();
+        std::fs::create_dir_all(dependency_prefix.join("share/info")).unwrap();
+        std::fs::write(dependency_prefix.join("share/info/dir"), "gcc").unwrap();
+        std::fs::write(dependency_prefix.join("copied"), "content").unwrap();
+        std::fs::write(output_prefix.join("share/info/dir"), "gcc\nbinutils").unwrap();
+        std::fs::write(output_prefix.join("copied"), "content").unwrap();
+        set_mtime(&dependency_prefix.join("copied"), 1000);
+        set_mtime(&output_prefix.join("copied"), 1000);
+
+        let overridden_paths = exclude_dependency_files(
+            &output_prefix,
+            &[&dependency_prefix],
+            MountBackend::KernelOverlayfs,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(overridden_paths, vec![PathBuf::from("share/info/dir")]);
+        assert_eq!(
+            std::fs::read_to_string(output_prefix.join("share/info/dir")).unwrap(),
+            "gcc\nbinutils"
+        );
+        assert!(!output_prefix.join("copied").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exclude_dependency_files_compares_with_highest_precedence() {
+        let dir = temp_dir();
+        let output_prefix = dir.join("output");
+        let winning_prefix = dir.join("winning");
+        let losing_prefix = dir.join("losing");
+        for prefix in [&output_prefix, &winning_prefix, &losing_prefix] {
+            std::fs::create_dir_all(prefix).unwrap();
+        }
+        std::fs::write(winning_prefix.join("file"), "winning").unwrap();
+        std::fs::write(losing_prefix.join("file"), "losing").unwrap();
+        std::fs::write(output_prefix.join("file"), "winning").unwrap();
+        set_mtime(&winning_prefix.join("file"), 1000);
+        set_mtime(&losing_prefix.join("file"), 1000);
+        set_mtime(&output_prefix.join("file"), 1000);
+
+        let overridden_paths = exclude_dependency_files(
+            &output_prefix,
+            &[&winning_prefix, &losing_prefix],
+            MountBackend::KernelOverlayfs,
+        )
+        .await
+        .unwrap();
+
+        assert!(overridden_paths.is_empty());
+        assert!(!output_prefix.join("file").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use tokio::fs;
+
+use crate::{
+    bake::BakeOptions,
+    event::{Event, MessageLevel},
+    recipe::{ResolvedRecipeRef, ResolvedRecipeSet},
+    state::State,
+};
+
+/// The recipes installed in a profile generation. Each generation's prefix
+/// is a tree of symlinks into the prefixes of these recipes.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileManifest {
+    /// Installed recipes, keyed by recipe name.
+    pub recipes: BTreeMap<String, InstalledRecipe>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstalledRecipe {
+    pub version: String,
+    pub recipe_ref: ResolvedRecipeRef,
+}
+
+/// Bake a recipe and install it into the profile, replacing any installed
+/// recipe with the same name.
+pub async fn install(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    options: &BakeOptions,
+) -> anyhow::Result<()> {
+    let recipe = recipe_set.get(recipe_ref);
+    crate::bake::get_baked_recipe(state, recipe_set, recipe_ref, options).await?;
+
+    let mut manifest = current_manifest(state).await?;
+    let previous = manifest.recipes.insert(
+        recipe.name.clone(),
+        InstalledRecipe {
+            version: recipe.version.clone(),
+            recipe_ref: *recipe_ref,
+        },
+    );
+    if let Some(previous) = previous {
+        if previous.recipe_ref == *recipe_ref {
+            state.emit(Event::Message {
+                level: MessageLevel::Info,
+                message: format!(
+                    "Recipe {} {} is already installed",
+                    recipe.name, recipe.version
+                ),
+                details: vec![],
+            });
+            return Ok(());
+        }
+    }
+
+    let generation = new_generation(state, &manifest).await?;
+    state.emit(Event::Message {
+        level: MessageLevel::Info,
+        message: format!(
+            "Installed {} {} (generation {})",
+            recipe.name, recipe.version, generation
+        ),
+        details: vec![],
+    });
+
+    Ok(())
+}
+
+/// Remove an installed recipe from the profile by name.
+pub async fn uninstall(state: &State, name: &str) -> anyhow::Result<()> {
+    let mut manifest = current_manifest(state).await?;
+    let removed = match manifest.recipes.remove(name) {
+        Some(removed) => removed,
+        None => {
+            anyhow::bail!("recipe {} is not installed", name);
+        }
+    };
+
+    let generation = new_generation(state, &manifest).await?;
+    state.emit(Event::Message {
+        level: MessageLevel::Info,
+        message: format!(
+            "Uninstalled {} {} (generation {})",
+            name, removed.version, generation
+        ),
+        details: vec![],
+    });
+
+    Ok(())
+}
+
+/// Switch the profile back to an earlier generation: either the given one,
+/// or the one before the current generation.
+pub async fn rollback(state: &State, generation: Option<u32>) -> anyhow::Result<()> {
+    let current_generation = match current_generation(state).await? {
+        Some(current_generation) => current_generation,
+        None => {
+            anyhow::bail!("nothing has been installed yet");
+        }
+    };
+
+    let generations = list_generations(state).await?;
+    let target_generation = match generation {
+        Some(generation) => {
+            if !generations.contains(&generation) {
+                anyhow::bail!("generation {} does not exist", generation);
+            }
+            generation
+        }
+        None => {
+            let previous_generation = generations
+                .iter()
+                .copied()
+                .filter(|&generation| generation < current_generation)
+                .max();
+            match previous_generation {
+                Some(previous_generation) => previous_generation,
+                None => {
+                    anyhow::bail!("generation {} is the oldest generation", current_generation);
+                }
+            }
+        }
+    };
+
+    switch_generation(state, target_generation).await?;
+    state.emit(Event::Message {
+        level: MessageLevel::Info,
+        message: format!(
+            "Switched from generation {} to generation {}",
+            current_generation, target_generation
+        ),
+        details: vec![],
+    });
+
+    Ok(())
+}
+
+fn generations_dir(state: &State) -> PathBuf {
+    state.profile_dir.join("generations")
+}
+
+fn generation_dir(state: &State, generation: u32) -> PathBuf {
+    generations_dir(state).join(generation.to_string())
+}
+
+fn current_link_path(state: &State) -> PathBuf {
+    state.profile_dir.join("current")
+}
+
+async fn current_generation(state: &State) -> anyhow::Result<Option<u32>> {
+    let target = match fs::read_link(current_link_path(state)).await {
+        Ok(target) => target,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(error) => {
+            return Err(error.into());
+        }
+    };
+
+    let generation = target
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.parse().ok());
+    match generation {
+        Some(generation) => Ok(Some(generation)),
+        None => {
+            anyhow::bail!("invalid current profile link to {}", target.display());
+        }
+    }
+}
+
+async fn current_manifest(state: &State) -> anyhow::Result<ProfileManifest> {
+    match current_generation(state).await? {
+        Some(generation) => read_manifest(&generation_dir(state, generation)).await,
+        None => Ok(ProfileManifest::default()),
+    }
+}
+
+pub async fn read_manifest(generation_dir: &Path) -> anyhow::Result<ProfileManifest> {
+    let manifest_json = fs::read(generation_dir.join("manifest.json")).await?;
+    let manifest = serde_json::from_slice(&manifest_json)?;
+    Ok(manifest)
+}
+
+async fn list_generations(state: &State) -> anyhow::Result<Vec<u32>> {
+    let mut generations = vec![];
+    let mut entries = match fs::read_dir(generations_dir(state)).await {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(vec![]);
+        }
+        Err(error) => {
+            return Err(error.into());
+        }
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(generation) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            generations.push(generation);
+        }
+    }
+
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+/// Create a new generation from a manifest, register it as a GC root, and
+/// switch to it. Returns the new generation number.
+async fn new_generation(state: &State, manifest: &ProfileManifest) -> anyhow::Result<u32> {
+    let generation = list_generations(state)
+        .await?
+        .last()
+        .map(|generation| generation + 1)
+        .unwrap_or(1);
+    let generation_dir = generation_dir(state, generation);
+
+    let result = write_generation(state, manifest, &generation_dir).await;
+    if let Err(error) = result {
+        let _ = fs::remove_dir_all(&generation_dir).await;
+        return Err(error);
+    }
+
+    let gc_root_path = state
+        .gc_roots_dir
+        .join(format!("profile-generation-{}", generation));
+    let _ = fs::remove_file(&gc_root_path).await;
+    fs::symlink(&generation_dir, &gc_root_path).await?;
+
+    switch_generation(state, generation).await?;
+
+    Ok(generation)
+}
+
+async fn write_generation(
+    state: &State,
+    manifest: &ProfileManifest,
+    generation_dir: &Path,
+) -> anyhow::Result<()> {
+    let generation_prefix_dir = generation_dir.join("prefix");
+    fs::create_dir_all(&generation_prefix_dir).await?;
+
+    let mut providers = BTreeMap::<PathBuf, &str>::new();
+    for (name, installed) in &manifest.recipes {
+        let prefix_path = match state.get_recipe_output(&installed.recipe_ref)? {
+            Some(prefix_path) => prefix_path,
+            None => {
+                anyhow::bail!("installed recipe {} {} is missing", name, installed.version);
+            }
+        };
+
+        for relative_path in link_prefix(&prefix_path, &generation_prefix_dir).await? {
+            if let Some(other_name) = providers.insert(relative_path.clone(), name) {
+                anyhow::bail!(
+                    "{} is provided by both {} and {}",
+                    relative_path.display(),
+                    other_name,
+                    name
+                );
+            }
+        }
+    }
+
+    let manifest_json = serde_json::to_vec_pretty(manifest)?;
+    fs::write(generation_dir.join("manifest.json"), &manifest_json).await?;
+
+    Ok(())
+}
+
+async fn switch_generation(state: &State, generation: u32) -> anyhow::Result<()> {
+    // Replace the link atomically, so the profile is never missing
+    let current_link_path = current_link_path(state);
+    let temp_link_path = state.profile_dir.join("current.tmp");
+    let _ = fs::remove_file(&temp_link_path).await;
+    fs::symlink(generation_dir(state, generation), &temp_link_path).await?;
+    fs::rename(&temp_link_path, &current_link_path).await?;
+
+    Ok(())
+}
+
+/// Mirror a recipe prefix into a profile prefix, with real directories and
+/// symlinks for everything else. Returns the relative path of each symlink.
+async fn link_prefix(
+    prefix_path: &Path,
+    profile_prefix_path: &Path,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut links = vec![];
+    let mut pending_dirs = vec![PathBuf::new()];
+    while let Some(relative_dir) = pending_dirs.pop() {
+        let mut entries = fs::read_dir(prefix_path.join(&relative_dir)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let relative_path = relative_dir.join(entry.file_name());
+            let profile_path = profile_prefix_path.join(&relative_path);
+
+            if entry.file_type().await?.is_dir() {
+                fs::create_dir_all(&profile_path).await?;
+                pending_dirs.push(relative_path);
+            } else if fs::symlink_metadata(&profile_path).await.is_ok() {
+                // Conflicts get reported by the caller
+                links.push(relative_path);
+            } else {
+                fs::symlink(entry.path(), &profile_path).await?;
+                links.push(relative_path);
+            }
+        }
+    }
+
+    Ok(links)
+}
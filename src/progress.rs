@@ -0,0 +1,400 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::Write as _,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use url::Url;
+
+use crate::{
+    event::{Event, EventSink, MessageLevel},
+    format::{format_duration, format_size},
+    recipe::ResolvedRecipeRef,
+};
+
+/// How many log lines to keep for each running build. The whole tail gets
+/// printed if the build fails.
+const LOG_TAIL_LINES: usize = 20;
+
+/// How many log lines to show for each running build while it's running.
+const LIVE_LOG_TAIL_LINES: usize = 3;
+
+/// The minimum time between redraws for frequent events (log lines and
+/// download progress).
+const RENDER_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often to redraw while work is running, to keep elapsed times current.
+const TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Check if stderr is a terminal that can show progress.
+pub fn is_terminal() -> bool {
+    let is_tty = nix::unistd::isatty(nix::libc::STDERR_FILENO).unwrap_or(false);
+    let is_dumb = match std::env::var_os("TERM") {
+        Some(term) => term == "dumb",
+        None => true,
+    };
+    is_tty && !is_dumb
+}
+
+/// Renders events as a live status display on stderr: one line for each
+/// running build (followed by the tail of its log) and each running
+/// download. Finished work is printed as regular lines above the display.
+#[derive(Debug)]
+pub struct ProgressEventSink {
+    state: Arc<Mutex<ProgressState>>,
+}
+
+impl ProgressEventSink {
+    pub fn new() -> Self {
+        let state = Arc::new(Mutex::new(ProgressState::default()));
+
+        // The thread stops once the sink is dropped
+        let weak_state = Arc::downgrade(&state);
+        std::thread::spawn(move || tick(weak_state));
+
+        Self { state }
+    }
+}
+
+impl EventSink for ProgressEventSink {
+    fn emit(&self, event: Event) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.handle(event);
+    }
+}
+
+fn tick(state: Weak<Mutex<ProgressState>>) {
+    loop {
+        std::thread::sleep(TICK_INTERVAL);
+
+        let state = match state.upgrade() {
+            Some(state) => state,
+            None => {
+                return;
+            }
+        };
+        let mut state = match state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if state.is_active() && !crate::bootstrap_env::has_interactive_child() {
+            state.render(&[]);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProgressState {
+    resolving: Vec<String>,
+    builds: BTreeMap<ResolvedRecipeRef, BuildProgress>,
+    downloads: BTreeMap<Url, DownloadProgress>,
+    num_cached: usize,
+    rendered_lines: usize,
+    last_render: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct BuildProgress {
+    name: String,
+    version: String,
+    started: Instant,
+    log_tail: VecDeque<String>,
+}
+
+#[derive(Debug)]
+struct DownloadProgress {
+    started: Instant,
+    downloaded_bytes: u64,
+    total_bytes: Option<u64>,
+}
+
+impl ProgressState {
+    fn is_active(&self) -> bool {
+        !self.resolving.is_empty() || !self.builds.is_empty() || !self.downloads.is_empty()
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::ResolveStarted { name } => {
+                self.resolving.push(name);
+                self.render_throttled();
+            }
+            Event::ResolveFinished { .. } | Event::ResolveFailed { .. } => {
+                self.resolving.pop();
+                self.render_throttled();
+            }
+            Event::DownloadStarted { url, total_bytes } => {
+                self.downloads.insert(
+                    url,
+                    DownloadProgress {
+                        started: Instant::now(),
+                        downloaded_bytes: 0,
+                        total_bytes,
+                    },
+                );
+                self.render(&[]);
+            }
+            Event::DownloadProgress {
+                url,
+                downloaded_bytes,
+                total_bytes,
+            } => {
+                if let Some(download) = self.downloads.get_mut(&url) {
+                    download.downloaded_bytes = downloaded_bytes;
+                    download.total_bytes = total_bytes;
+                }
+                self.render_throttled();
+            }
+            Event::DownloadFinished { url, .. } => {
+                let line = match self.downloads.remove(&url) {
+                    Some(download) => format!(
+                        "Downloaded {} ({} in {})",
+                        url,
+                        format_size(download.downloaded_bytes),
+                        format_duration(download.started.elapsed())
+                    ),
+                    None => format!("Downloaded {}", url),
+                };
+                self.render(&[line]);
+            }
+            Event::DownloadFailed { url, error } => {
+                self.downloads.remove(&url);
+                let line = format!("Failed to download {}: {}", url, error);
+                self.render(&[line]);
+            }
+            Event::CheckoutFinished {
+                repo,
+                git_ref,
+                commit,
+                ..
+            } => {
+                let line = format!("Checked out {} @ {} ({})", repo, git_ref, commit);
+                self.render(&[line]);
+            }
+            Event::Unpacked { content_hash, .. } => {
+                let line = format!("Unpacked {}", content_hash);
+                self.render(&[line]);
+            }
+            Event::CacheHit { .. } => {
+                self.num_cached += 1;
+                self.render_throttled();
+            }
+            Event::BuildStarted {
+                recipe_ref,
+                name,
+                version,
+            } => {
+                self.builds.insert(
+                    recipe_ref,
+                    BuildProgress {
+                        name,
+                        version,
+                        started: Instant::now(),
+                        log_tail: VecDeque::new(),
+                    },
+                );
+                self.render(&[]);
+            }
+            Event::LogLine {
+                recipe_ref, line, ..
+            } => {
+                if let Some(build) = self.builds.get_mut(&recipe_ref) {
+                    if build.log_tail.len() == LOG_TAIL_LINES {
+                        build.log_tail.pop_front();
+                    }
+                    build.log_tail.push_back(sanitize(&line));
+                }
+                self.render_throttled();
+            }
+            Event::BuildFinished {
+                recipe_ref,
+                name,
+                version,
+                duration,
+                ..
+            } => {
+                self.builds.remove(&recipe_ref);
+                let line = format!(
+                    "Baked {} {} in {}",
+                    name,
+                    version,
+                    format_duration(duration)
+                );
+                self.render(&[line]);
+            }
+            Event::BuildFailed {
+                recipe_ref,
+                name,
+                version,
+                duration,
+                ..
+            } => {
+                let mut lines = vec![format!(
+                    "Failed to bake {} {} after {}",
+                    name,
+                    version,
+                    format_duration(duration)
+                )];
+                if let Some(build) = self.builds.remove(&recipe_ref) {
+                    lines.extend(build.log_tail.iter().map(|line| format!("  | {}", line)));
+                }
+                self.render(&lines);
+            }
+            Event::LockfileUpdated { updated } => {
+                if updated {
+                    self.render(&["Updated lockfile".to_string()]);
+                }
+            }
+            Event::RecipeReady {
+                name,
+                version,
+                prefix_path,
+                ..
+            } => {
+                let line = format!("Built {} {} to {}", name, version, prefix_path.display());
+                self.render(&[line]);
+            }
+            Event::Message {
+                level,
+                message,
+                details,
+            } => {
+                let mut lines = vec![match level {
+                    MessageLevel::Info => message,
+                    MessageLevel::Warning => format!("Warning: {}", message),
+                }];
+                lines.extend(details.iter().map(|detail| format!("  {}", detail)));
+                self.render(&lines);
+            }
+        }
+    }
+
+    fn render_throttled(&mut self) {
+        let should_render = match self.last_render {
+            Some(last_render) => last_render.elapsed() >= RENDER_INTERVAL,
+            None => true,
+        };
+        if should_render {
+            self.render(&[]);
+        }
+    }
+
+    /// Clear the live display, print any finished lines, then draw the live
+    /// display again below them.
+    ///
+    /// While an interactive child is attached to the terminal, only the
+    /// finished lines get printed, so nothing the child printed gets
+    /// cleared or drawn over. The live display gets drawn from scratch once
+    /// the child exits.
+    fn render(&mut self, finished_lines: &[String]) {
+        let width = terminal_width();
+        let mut output = String::new();
+
+        let is_paused = crate::bootstrap_env::has_interactive_child();
+        if is_paused {
+            self.rendered_lines = 0;
+        } else if self.rendered_lines > 0 {
+            output.push_str(&format!("\r\x1b[{}A\x1b[J", self.rendered_lines));
+        } else {
+            output.push_str("\r\x1b[J");
+        }
+
+        for line in finished_lines {
+            output.push_str(line);
+            output.push('\n');
+        }
+
+        if is_paused {
+            let stderr = std::io::stderr();
+            let mut stderr = stderr.lock();
+            let _ = stderr.write_all(output.as_bytes());
+            let _ = stderr.flush();
+
+            self.last_render = Some(Instant::now());
+            return;
+        }
+
+        let mut live_lines = vec![];
+        for build in self.builds.values() {
+            live_lines.push(format!(
+                "[bake] {} {} ({})",
+                build.name,
+                build.version,
+                format_duration(build.started.elapsed())
+            ));
+            let num_skipped = build.log_tail.len().saturating_sub(LIVE_LOG_TAIL_LINES);
+            for line in build.log_tail.iter().skip(num_skipped) {
+                live_lines.push(format!("  | {}", line));
+            }
+        }
+        for (url, download) in &self.downloads {
+            let total = match download.total_bytes {
+                Some(total_bytes) => format!(" / {}", format_size(total_bytes)),
+                None => String::new(),
+            };
+            live_lines.push(format!(
+                "[download] {} {}{} ({})",
+                url,
+                format_size(download.downloaded_bytes),
+                total,
+                format_duration(download.started.elapsed())
+            ));
+        }
+        if let Some(name) = self.resolving.last() {
+            live_lines.push(format!("[resolve] {}", name));
+        }
+        if self.num_cached > 0 && self.is_active() {
+            live_lines.push(format!("{} recipes already baked", self.num_cached));
+        }
+
+        // Lines that wrap would throw off the number of lines to clear
+        for line in &live_lines {
+            output.extend(line.chars().take(width.saturating_sub(1)));
+            output.push('\n');
+        }
+
+        let stderr = std::io::stderr();
+        let mut stderr = stderr.lock();
+        let _ = stderr.write_all(output.as_bytes());
+        let _ = stderr.flush();
+
+        self.rendered_lines = live_lines.len();
+        self.last_render = Some(Instant::now());
+    }
+}
+
+/// Strip control characters (like color codes and carriage returns) from
+/// a log line, so it takes up exactly one line in the display.
+fn sanitize(line: &str) -> String {
+    line.chars()
+        .map(|c| if c == '\t' { ' ' } else { c })
+        .filter(|c| !c.is_control())
+        .collect()
+}
+
+fn terminal_width() -> usize {
+    let mut winsize = nix::libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    // SAFETY: `TIOCGWINSZ` only writes to the `winsize` struct
+    let result = unsafe {
+        nix::libc::ioctl(
+            nix::libc::STDERR_FILENO,
+            nix::libc::TIOCGWINSZ,
+            &mut winsize,
+        )
+    };
+    if result == 0 && winsize.ws_col > 0 {
+        winsize.ws_col as usize
+    } else {
+        80
+    }
+}
@@ -2,6 +2,7 @@ use std::{
     collections::{BTreeMap, BTreeSet, HashMap},
     fmt::Display,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use tokio::{fs::File, io::AsyncReadExt as _};
@@ -9,12 +10,43 @@ use url::Url;
 
 use crate::{hash::Hash, state::State};
 
-#[async_recursion::async_recursion]
 pub async fn resolve_recipe(
     state: &State,
     repo: &Path,
     name: &str,
     recipe_set: &mut ResolvedRecipeSet,
+) -> anyhow::Result<ResolvedRecipeRef> {
+    state.emit(crate::event::Event::ResolveStarted {
+        name: name.to_string(),
+    });
+
+    let result = resolve_recipe_inner(state, repo, name, recipe_set).await;
+    match &result {
+        Ok(recipe_ref) => {
+            let recipe = recipe_set.get(recipe_ref);
+            state.emit(crate::event::Event::ResolveFinished {
+                name: recipe.name.clone(),
+                version: recipe.version.clone(),
+                recipe_ref: *recipe_ref,
+            });
+        }
+        Err(error) => {
+            state.emit(crate::event::Event::ResolveFailed {
+                name: name.to_string(),
+                error: format!("{:#}", error),
+            });
+        }
+    }
+
+    result
+}
+
+#[async_recursion::async_recursion]
+async fn resolve_recipe_inner(
+    state: &State,
+    repo: &Path,
+    name: &str,
+    recipe_set: &mut ResolvedRecipeSet,
 ) -> anyhow::Result<ResolvedRecipeRef> {
     let recipe = eval_recipe(repo.join(name)).await?;
 
@@ -42,15 +74,52 @@ pub async fn resolve_recipe(
         resolved_dependencies.insert(resolved_dependency);
     }
 
+    let mut resolved_build_dependencies = BTreeSet::new();
+    for (dependency_name, _dependency_version) in recipe.build_dependencies.iter().flatten() {
+        // TODO: Use dependency version to resolve dependency
+        let resolved_dependency = resolve_recipe(state, repo, dependency_name, recipe_set).await?;
+        resolved_build_dependencies.insert(resolved_dependency);
+    }
+
+    let conflict_policy = match &recipe.conflicts {
+        Some(conflicts) => {
+            let env_dependency_names = env_dependency_names(
+                recipe_set,
+                &resolved_dependencies,
+                &resolved_build_dependencies,
+            );
+            conflicts.to_policy(|name| env_dependency_names.contains(name))?
+        }
+        None => ConflictPolicy::default(),
+    };
+
     let resolved_recipe = ResolvedRecipe {
         name: recipe.name,
         version: recipe.version,
         source: resolved_source_ref,
         dependencies: resolved_dependencies,
+        build_dependencies: resolved_build_dependencies,
+        conflict_policy,
         build: recipe.build,
     };
 
-    Ok(recipe_set.insert(resolved_recipe))
+    let recipe_ref = recipe_set.insert(resolved_recipe);
+    Ok(recipe_ref)
+}
+
+/// Get the names of every recipe that gets layered into the bootstrap env
+/// for a recipe with the given dependencies, the same set as
+/// `ResolvedRecipeSet::env_dependencies`.
+fn env_dependency_names(
+    recipe_set: &ResolvedRecipeSet,
+    dependencies: &BTreeSet<ResolvedRecipeRef>,
+    build_dependencies: &BTreeSet<ResolvedRecipeRef>,
+) -> BTreeSet<String> {
+    recipe_set
+        .runtime_closure(dependencies.iter().chain(build_dependencies.iter()))
+        .iter()
+        .map(|dependency_ref| recipe_set.get(dependency_ref).name.clone())
+        .collect()
 }
 
 async fn eval_recipe(path: impl AsRef<Path>) -> anyhow::Result<RecipeDefinition> {
@@ -86,7 +155,14 @@ pub struct RecipeDefinition {
     pub name: String,
     pub version: String,
     pub source: RecipeSource,
+    /// Runtime dependencies, which are also available to anything that
+    /// depends on this recipe.
     pub dependencies: HashMap<String, String>,
+    /// Dependencies only needed while building this recipe.
+    #[quickjs(rename = "buildDependencies")]
+    #[serde(rename = "buildDependencies")]
+    pub build_dependencies: Option<HashMap<String, String>>,
+    pub conflicts: Option<RecipeConflicts>,
     pub build: RecipeBuildScript,
 }
 
@@ -105,13 +181,59 @@ pub enum RecipeSource {
     },
 }
 
-#[derive(Debug, Clone, serde::Serialize, rquickjs::FromJs)]
+/// How to handle files provided by more than one dependency, e.g.
+/// `{ policy: "priority", priority: ["gcc", "binutils"] }`.
+#[derive(Debug, serde::Serialize, rquickjs::FromJs)]
+pub struct RecipeConflicts {
+    pub policy: String,
+    pub priority: Option<Vec<String>>,
+}
+
+impl RecipeConflicts {
+    fn to_policy(&self, is_dependency: impl Fn(&str) -> bool) -> anyhow::Result<ConflictPolicy> {
+        match (&*self.policy, &self.priority) {
+            ("last-wins", None) => Ok(ConflictPolicy::LastWins),
+            ("error", None) => Ok(ConflictPolicy::Error),
+            ("first-wins", None) => Ok(ConflictPolicy::FirstWins),
+            ("priority", Some(priority)) => {
+                for dependency_name in priority {
+                    if !is_dependency(dependency_name) {
+                        anyhow::bail!(
+                            "conflict priority lists {:?}, which is not a dependency or a runtime dependency of one",
+                            dependency_name
+                        );
+                    }
+                }
+
+                Ok(ConflictPolicy::Priority(priority.clone()))
+            }
+            ("priority", None) => {
+                anyhow::bail!("conflict policy \"priority\" requires a priority list");
+            }
+            ("last-wins" | "error" | "first-wins", Some(_)) => {
+                anyhow::bail!("a priority list can only be used with conflict policy \"priority\"");
+            }
+            (policy, _) => {
+                anyhow::bail!(
+                    "unknown conflict policy {:?} (expected \"last-wins\", \"error\", \"first-wins\", or \"priority\")",
+                    policy
+                );
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, rquickjs::FromJs)]
 #[quickjs(rename_all = "camelCase")]
 #[serde(rename_all = "camelCase")]
 pub struct RecipeBuildScript {
     pub shell: String,
     pub script: String,
     pub env_vars: HashMap<String, String>,
+    /// How long the build script can run before it gets killed, in seconds.
+    /// This doesn't affect the output, so it isn't part of the recipe hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u32>,
 }
 
 #[derive(
@@ -134,6 +256,15 @@ impl Display for ResolvedRecipeRef {
     }
 }
 
+impl FromStr for ResolvedRecipeRef {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hash = s.parse()?;
+        Ok(Self { hash })
+    }
+}
+
 #[derive(Debug)]
 pub struct ResolvedRecipeSet {
     definitions: BTreeMap<ResolvedRecipeRef, ResolvedRecipe>,
@@ -154,6 +285,36 @@ impl ResolvedRecipeSet {
             .expect("Recipe reference not found in recipe set")
     }
 
+    /// Get every recipe that should be available while baking a recipe: its
+    /// build and runtime dependencies, along with all of their runtime
+    /// dependencies (transitively).
+    pub fn env_dependencies(&self, recipe_ref: &ResolvedRecipeRef) -> BTreeSet<ResolvedRecipeRef> {
+        let recipe = self.get(recipe_ref);
+        let direct_dependencies = recipe
+            .dependencies
+            .iter()
+            .chain(recipe.build_dependencies.iter());
+        self.runtime_closure(direct_dependencies)
+    }
+
+    /// Get the given recipes along with all of their runtime dependencies
+    /// (transitively).
+    pub fn runtime_closure<'a>(
+        &self,
+        recipe_refs: impl IntoIterator<Item = &'a ResolvedRecipeRef>,
+    ) -> BTreeSet<ResolvedRecipeRef> {
+        let mut closure = BTreeSet::new();
+        let mut pending_refs: Vec<_> = recipe_refs.into_iter().copied().collect();
+        while let Some(recipe_ref) = pending_refs.pop() {
+            if closure.insert(recipe_ref) {
+                let recipe = self.get(&recipe_ref);
+                pending_refs.extend(recipe.dependencies.iter().copied());
+            }
+        }
+
+        closure
+    }
+
     pub fn get_source(&self, source_ref: &ResolvedRecipeSourceRef) -> &ResolvedRecipeSource {
         self.sources
             .get(source_ref)
@@ -163,7 +324,11 @@ impl ResolvedRecipeSet {
     fn insert(&mut self, recipe: ResolvedRecipe) -> ResolvedRecipeRef {
         use sha2::Digest as _;
 
-        let cjson_bytes = cjson::to_vec(&recipe).expect("Failed to canonicalize JSON");
+        // The timeout doesn't affect the output, so it's left out of the hash
+        // (but still gets saved with the recipe's metadata)
+        let mut hashed_recipe = recipe.clone();
+        hashed_recipe.build.timeout = None;
+        let cjson_bytes = cjson::to_vec(&hashed_recipe).expect("Failed to canonicalize JSON");
 
         let mut cjson_hash = sha2::Sha256::new();
         cjson_hash.update(&cjson_bytes);
@@ -179,7 +344,7 @@ impl ResolvedRecipeSet {
     fn insert_source(&mut self, source: ResolvedRecipeSource) -> ResolvedRecipeSourceRef {
         let source_ref = match source {
             ResolvedRecipeSource::Git(ref git_checkout) => ResolvedRecipeSourceRef::Git {
-                commit: git_checkout.commit.to_string(),
+                hash: git_checkout.tree_hash,
             },
             ResolvedRecipeSource::Tarball(ref tarball_file) => ResolvedRecipeSourceRef::Tarball {
                 hash: tarball_file.content_hash,
@@ -192,23 +357,153 @@ impl ResolvedRecipeSet {
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ResolvedRecipe {
     pub name: String,
     pub version: String,
     pub source: ResolvedRecipeSourceRef,
     pub dependencies: BTreeSet<ResolvedRecipeRef>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub build_dependencies: BTreeSet<ResolvedRecipeRef>,
+    #[serde(default, skip_serializing_if = "ConflictPolicy::is_default")]
+    pub conflict_policy: ConflictPolicy,
     pub build: RecipeBuildScript,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize)]
+/// How files provided by more than one dependency get resolved when the
+/// dependencies are layered together. Dependencies are identified by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictPolicy {
+    /// Use the file from the dependency whose name sorts last, like when
+    /// dependencies were copied into the prefix one after another.
+    #[default]
+    LastWins,
+    /// Fail the build if any dependencies conflict.
+    Error,
+    /// Use the file from the dependency whose name sorts first.
+    FirstWins,
+    /// Use the file from whichever dependency comes first in the list.
+    /// Conflicts between dependencies not in the list are still an error.
+    Priority(Vec<String>),
+}
+
+impl ConflictPolicy {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
 pub enum ResolvedRecipeSourceRef {
-    Git { commit: String },
+    Git { hash: Hash },
     Tarball { hash: Hash },
 }
 
+impl Display for ResolvedRecipeSourceRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolvedRecipeSourceRef::Git { hash } => write!(f, "git tree {}", hash),
+            ResolvedRecipeSourceRef::Tarball { hash } => write!(f, "tarball {}", hash),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ResolvedRecipeSource {
     Git(crate::state::GitCheckout),
     Tarball(crate::state::ContentFile),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recipe(timeout: Option<u32>) -> ResolvedRecipe {
+        ResolvedRecipe {
+            name: "hello".to_string(),
+            version: "1.0".to_string(),
+            source: ResolvedRecipeSourceRef::Tarball {
+                hash: Hash::default(),
+            },
+            dependencies: BTreeSet::new(),
+            build_dependencies: BTreeSet::new(),
+            conflict_policy: ConflictPolicy::default(),
+            build: RecipeBuildScript {
+                shell: "/bin/sh".to_string(),
+                script: "make install".to_string(),
+                env_vars: HashMap::new(),
+                timeout,
+            },
+        }
+    }
+
+    #[test]
+    fn test_timeout_is_not_hashed() {
+        let mut recipe_set = ResolvedRecipeSet::new();
+        let recipe_ref = recipe_set.insert(recipe(None));
+        let recipe_with_timeout_ref = recipe_set.insert(recipe(Some(60)));
+        assert_eq!(recipe_ref, recipe_with_timeout_ref);
+
+        let recipe_json = serde_json::to_value(recipe(Some(60))).unwrap();
+        assert_eq!(recipe_json["build"]["timeout"], 60);
+        let recipe_json = serde_json::to_value(recipe(None)).unwrap();
+        assert!(recipe_json["build"].get("timeout").is_none());
+    }
+
+    #[test]
+    fn test_env_dependency_names() {
+        let named = |name: &str, dependencies: Vec<ResolvedRecipeRef>| ResolvedRecipe {
+            name: name.to_string(),
+            dependencies: dependencies.into_iter().collect(),
+            ..recipe(None)
+        };
+
+        let mut recipe_set = ResolvedRecipeSet::new();
+        let glibc = recipe_set.insert(named("glibc", vec![]));
+        let gcc = recipe_set.insert(named("gcc", vec![glibc]));
+        let make = recipe_set.insert(named("make", vec![]));
+
+        let names =
+            env_dependency_names(&recipe_set, &BTreeSet::from([make]), &BTreeSet::from([gcc]));
+        assert_eq!(
+            names,
+            BTreeSet::from(["gcc".to_string(), "glibc".to_string(), "make".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_conflict_policy() {
+        let conflicts = |policy: &str, priority: Option<Vec<&str>>| RecipeConflicts {
+            policy: policy.to_string(),
+            priority: priority.map(|priority| priority.into_iter().map(String::from).collect()),
+        };
+        let is_dependency = |name: &str| name == "gcc";
+
+        assert_eq!(ConflictPolicy::default(), ConflictPolicy::LastWins);
+        assert_eq!(
+            conflicts("last-wins", None)
+                .to_policy(is_dependency)
+                .unwrap(),
+            ConflictPolicy::LastWins
+        );
+        assert_eq!(
+            conflicts("error", None).to_policy(is_dependency).unwrap(),
+            ConflictPolicy::Error
+        );
+        assert_eq!(
+            conflicts("priority", Some(vec!["gcc"]))
+                .to_policy(is_dependency)
+                .unwrap(),
+            ConflictPolicy::Priority(vec!["gcc".to_string()])
+        );
+        assert!(conflicts("priority", Some(vec!["clang"]))
+            .to_policy(is_dependency)
+            .is_err());
+        assert!(conflicts("last-wins", Some(vec!["gcc"]))
+            .to_policy(is_dependency)
+            .is_err());
+    }
+}
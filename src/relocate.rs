@@ -0,0 +1,662 @@
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt as _,
+    path::{Path, PathBuf},
+};
+
+use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+/// The longest shebang line the kernel will read (not including the `#!`).
+const MAX_SHEBANG_LENGTH: usize = 127;
+
+/// How much of a file to read at a time when scanning it.
+const SCAN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Rewrite references to the build prefix in text files, symlinks and ELF
+/// files to whichever layer provides the referenced file: the output itself
+/// or one of `dependency_prefixes` (highest precedence first).
+pub async fn relocate_output(
+    output_prefix: &Path,
+    build_prefix: &Path,
+    store_prefix: &Path,
+    dependency_prefixes: &[&Path],
+) -> anyhow::Result<RelocationReport> {
+    let own_layer = RelocationLayer {
+        host_path: output_prefix.to_owned(),
+        store_prefix: store_prefix.as_os_str().as_bytes().to_vec(),
+    };
+    let dependency_layers = dependency_prefixes.iter().map(|prefix| RelocationLayer {
+        host_path: prefix.to_path_buf(),
+        store_prefix: prefix.as_os_str().as_bytes().to_vec(),
+    });
+    let mut relocation = Relocation {
+        build_prefix: Finder::new(build_prefix.as_os_str().as_bytes()),
+        layers: std::iter::once(own_layer)
+            .chain(dependency_layers)
+            .collect(),
+        patchelf: None,
+        report: RelocationReport::default(),
+    };
+
+    let mut pending_paths = vec![output_prefix.to_owned()];
+    while let Some(path) = pending_paths.pop() {
+        let metadata = tokio::fs::symlink_metadata(&path).await?;
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            let mut entries = tokio::fs::read_dir(&path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                pending_paths.push(entry.path());
+            }
+        } else if file_type.is_symlink() {
+            relocation.relocate_symlink(&path).await?;
+        } else if file_type.is_file() {
+            let relative_path = path.strip_prefix(output_prefix)?.to_owned();
+            let relocated = relocation.relocate_file(&path, &metadata).await?;
+            match relocated {
+                FileRelocation::Relocated => {}
+                FileRelocation::LongShebang => {
+                    relocation.report.long_shebang_paths.push(relative_path);
+                }
+                FileRelocation::Unrelocated => {
+                    relocation.report.unrelocated_paths.push(relative_path);
+                }
+            }
+        }
+    }
+
+    relocation.report.unrelocated_paths.sort();
+    relocation.report.long_shebang_paths.sort();
+    Ok(relocation.report)
+}
+
+/// Files in an output that couldn't be relocated fully, relative to the
+/// output prefix.
+#[derive(Debug, Default)]
+pub struct RelocationReport {
+    /// Files that still reference the build prefix, such as binaries with
+    /// paths embedded in their data.
+    pub unrelocated_paths: Vec<PathBuf>,
+    /// Scripts whose relocated shebang line is longer than the kernel will
+    /// read, so it may get truncated.
+    pub long_shebang_paths: Vec<PathBuf>,
+}
+
+enum FileRelocation {
+    Relocated,
+    LongShebang,
+    Unrelocated,
+}
+
+/// One of the layers that made up the build prefix.
+struct RelocationLayer {
+    /// Where the layer's files are on the host right now.
+    host_path: PathBuf,
+    /// What references to the layer's files should be rewritten to.
+    store_prefix: Vec<u8>,
+}
+
+struct Relocation {
+    build_prefix: Finder,
+    /// The recipe's own output, followed by its dependencies from highest
+    /// to lowest precedence.
+    layers: Vec<RelocationLayer>,
+    patchelf: Option<PathBuf>,
+    report: RelocationReport,
+}
+
+/// What a scan of a file found.
+struct FileScan {
+    is_elf: bool,
+    is_text: bool,
+    references_build_prefix: bool,
+}
+
+impl Relocation {
+    async fn relocate_symlink(&self, path: &Path) -> anyhow::Result<()> {
+        let target = tokio::fs::read_link(path).await?;
+        let target_bytes = target.as_os_str().as_bytes();
+        if !target_bytes.starts_with(&self.build_prefix.needle) {
+            return Ok(());
+        }
+
+        let new_target = self.replace_references(target_bytes);
+        let new_target = Path::new(OsStr::from_bytes(&new_target));
+        tokio::fs::remove_file(path).await?;
+        tokio::fs::symlink(new_target, path).await?;
+
+        Ok(())
+    }
+
+    async fn relocate_file(
+        &mut self,
+        path: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> anyhow::Result<FileRelocation> {
+        let scan = self.scan_file(path).await?;
+        if !scan.references_build_prefix {
+            return Ok(FileRelocation::Relocated);
+        }
+
+        make_writable(path, metadata).await?;
+
+        if scan.is_elf {
+            self.relocate_elf(path).await?;
+
+            // Paths embedded anywhere else in the binary can't be changed
+            // without changing their length
+            let scan = self.scan_file(path).await?;
+            let relocation = if scan.references_build_prefix {
+                FileRelocation::Unrelocated
+            } else {
+                FileRelocation::Relocated
+            };
+            return Ok(relocation);
+        }
+
+        if !scan.is_text {
+            return Ok(FileRelocation::Unrelocated);
+        }
+
+        self.relocate_text_file(path).await
+    }
+
+    /// Read through a file a chunk at a time, stopping early once the
+    /// result is known.
+    async fn scan_file(&self, path: &Path) -> anyhow::Result<FileScan> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut buffer = vec![0; SCAN_CHUNK_SIZE];
+        let mut scan = FileScan {
+            is_elf: false,
+            is_text: true,
+            references_build_prefix: false,
+        };
+        let mut matched = 0;
+        let mut is_first_chunk = true;
+
+        loop {
+            let length = file.read(&mut buffer).await?;
+            if length == 0 {
+                break;
+            }
+            let chunk = &buffer[..length];
+
+            if is_first_chunk {
+                scan.is_elf = chunk.starts_with(ELF_MAGIC);
+                is_first_chunk = false;
+            }
+            if chunk.contains(&0) {
+                scan.is_text = false;
+            }
+            if !scan.references_build_prefix {
+                scan.references_build_prefix =
+                    self.build_prefix.find_end(chunk, &mut matched).is_some();
+            }
+
+            if scan.references_build_prefix && !scan.is_text {
+                break;
+            }
+        }
+
+        Ok(scan)
+    }
+
+    /// Rewrite a text file a line at a time into a new file next to it,
+    /// then replace the original.
+    async fn relocate_text_file(&self, path: &Path) -> anyhow::Result<FileRelocation> {
+        let mut file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("invalid path {}", path.display()))?
+            .to_owned();
+        file_name.push(".relocating");
+        let temp_path = path.with_file_name(file_name);
+
+        let file = tokio::fs::File::open(path).await?;
+        let mut reader = tokio::io::BufReader::new(file);
+        let temp_file = tokio::fs::File::create(&temp_path).await?;
+        let mut writer = tokio::io::BufWriter::new(temp_file);
+
+        let mut line = vec![];
+        let mut is_first_line = true;
+        let mut relocation = FileRelocation::Relocated;
+        loop {
+            line.clear();
+            let length = reader.read_until(b'\n', &mut line).await?;
+            if length == 0 {
+                break;
+            }
+
+            let new_line = self.replace_references(&line);
+            if is_first_line && new_line.starts_with(b"#!") {
+                let shebang_length = new_line
+                    .iter()
+                    .skip(2)
+                    .take_while(|&&byte| byte != b'\n')
+                    .count();
+                if shebang_length > MAX_SHEBANG_LENGTH {
+                    relocation = FileRelocation::LongShebang;
+                }
+            }
+            is_first_line = false;
+
+            writer.write_all(&new_line).await?;
+        }
+
+        writer.flush().await?;
+        drop(writer);
+
+        let permissions = tokio::fs::metadata(path).await?.permissions();
+        tokio::fs::set_permissions(&temp_path, permissions).await?;
+        tokio::fs::rename(&temp_path, path).await?;
+
+        Ok(relocation)
+    }
+
+    async fn relocate_elf(&mut self, path: &Path) -> anyhow::Result<()> {
+        // Not every ELF file has an interpreter or RPATH (e.g. static
+        // binaries and object files), so failing to read them is fine
+        if let Some(interpreter) = self.patchelf_output(path, "--print-interpreter").await? {
+            if interpreter.starts_with(&self.build_prefix.needle) {
+                let interpreter = self.replace_references(&interpreter);
+                self.patchelf(path, "--set-interpreter", &interpreter)
+                    .await?;
+            }
+        }
+
+        if let Some(rpath) = self.patchelf_output(path, "--print-rpath").await? {
+            if self.build_prefix.find(&rpath).is_some() {
+                let rpath = self.relocate_rpath(&rpath);
+                self.patchelf(path, "--set-rpath", &rpath).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn patchelf_output(
+        &mut self,
+        path: &Path,
+        flag: &str,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let output = tokio::process::Command::new(self.patchelf_path()?)
+            .arg(flag)
+            .arg(path)
+            .stderr(std::process::Stdio::null())
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        let mut value = output.stdout;
+        while value.last() == Some(&b'\n') {
+            value.pop();
+        }
+
+        Ok(Some(value))
+    }
+
+    async fn patchelf(&mut self, path: &Path, flag: &str, value: &[u8]) -> anyhow::Result<()> {
+        let patchelf_result = tokio::process::Command::new(self.patchelf_path()?)
+            .arg(flag)
+            .arg(OsStr::from_bytes(value))
+            .arg(path)
+            .status()
+            .await?;
+        if !patchelf_result.success() {
+            anyhow::bail!(
+                "patchelf {} exited with status {} for {}",
+                flag,
+                patchelf_result,
+                path.display()
+            );
+        }
+
+        Ok(())
+    }
+
+    fn patchelf_path(&mut self) -> anyhow::Result<PathBuf> {
+        if let Some(patchelf) = &self.patchelf {
+            return Ok(patchelf.clone());
+        }
+
+        let patchelf = which::which("patchelf").map_err(|error| {
+            anyhow::anyhow!("patchelf is required to relocate ELF files: {}", error)
+        })?;
+        self.patchelf = Some(patchelf.clone());
+        Ok(patchelf)
+    }
+
+    /// Replace each reference to the build prefix with the store prefix of
+    /// the layer that provides the referenced path.
+    fn replace_references(&self, bytes: &[u8]) -> Vec<u8> {
+        let prefix_length = self.build_prefix.needle.len();
+
+        let mut result = Vec::with_capacity(bytes.len());
+        let mut rest = bytes;
+        while let Some(index) = self.build_prefix.find(rest) {
+            result.extend_from_slice(&rest[..index]);
+            rest = &rest[index + prefix_length..];
+
+            let path_length = rest
+                .iter()
+                .position(|&byte| is_path_delimiter(byte))
+                .unwrap_or(rest.len());
+            let layer = self.providing_layer(&rest[..path_length]);
+            result.extend_from_slice(&layer.store_prefix);
+        }
+        result.extend_from_slice(rest);
+
+        result
+    }
+
+    /// Relocate each entry of an RPATH, expanding entries under the build
+    /// prefix to every layer that provides the dir.
+    fn relocate_rpath(&self, rpath: &[u8]) -> Vec<u8> {
+        let prefix = &self.build_prefix.needle;
+
+        let mut entries: Vec<Vec<u8>> = vec![];
+        for entry in rpath.split(|&byte| byte == b':') {
+            let relative_path = match entry.strip_prefix(&prefix[..]) {
+                Some(relative_path) => relative_path,
+                None => {
+                    entries.push(entry.to_vec());
+                    continue;
+                }
+            };
+
+            let mut layers: Vec<_> = self
+                .layers
+                .iter()
+                .filter(|layer| layer.provides(relative_path))
+                .collect();
+            if layers.is_empty() {
+                layers.push(&self.layers[0]);
+            }
+
+            for layer in layers {
+                let mut new_entry = layer.store_prefix.clone();
+                new_entry.extend_from_slice(relative_path);
+                entries.push(new_entry);
+            }
+        }
+
+        let mut seen_entries = std::collections::HashSet::new();
+        entries.retain(|entry| seen_entries.insert(entry.clone()));
+        entries.join(&b':')
+    }
+
+    /// Find the layer with the highest precedence that provides a path
+    /// relative to the build prefix, or else its closest existing ancestor.
+    fn providing_layer(&self, relative_path: &[u8]) -> &RelocationLayer {
+        let mut relative_path = relative_path;
+        while !relative_path.is_empty() {
+            let layer = self
+                .layers
+                .iter()
+                .find(|layer| layer.provides(relative_path));
+            if let Some(layer) = layer {
+                return layer;
+            }
+
+            let parent_length = relative_path
+                .iter()
+                .rposition(|&byte| byte == b'/')
+                .unwrap_or(0);
+            relative_path = &relative_path[..parent_length];
+        }
+
+        &self.layers[0]
+    }
+}
+
+impl RelocationLayer {
+    fn provides(&self, relative_path: &[u8]) -> bool {
+        let relative_path = Path::new(OsStr::from_bytes(relative_path));
+        let relative_path = match relative_path.strip_prefix("/") {
+            Ok(relative_path) => relative_path,
+            Err(_) => {
+                return false;
+            }
+        };
+        if relative_path.as_os_str().is_empty() {
+            return false;
+        }
+
+        std::fs::symlink_metadata(self.host_path.join(relative_path)).is_ok()
+    }
+}
+
+/// Check if a byte ends a path embedded in text, such as in a shell
+/// variable, a list of paths, or a quoted string.
+fn is_path_delimiter(byte: u8) -> bool {
+    byte.is_ascii_whitespace()
+        || matches!(
+            byte,
+            b'\0'
+                | b'"'
+                | b'\''
+                | b'`'
+                | b':'
+                | b';'
+                | b','
+                | b'='
+                | b'('
+                | b')'
+                | b'['
+                | b']'
+                | b'{'
+                | b'}'
+                | b'<'
+                | b'>'
+                | b'|'
+                | b'&'
+                | b'$'
+                | b'\\'
+        )
+}
+
+/// Searches for a byte string in linear time (using Knuth-Morris-Pratt), so
+/// a file can be searched a chunk at a time without keeping earlier chunks
+/// around.
+struct Finder {
+    needle: Vec<u8>,
+    /// For each prefix of the needle, the length of its longest proper
+    /// prefix that's also a suffix.
+    partial_matches: Vec<usize>,
+}
+
+impl Finder {
+    fn new(needle: &[u8]) -> Self {
+        let mut partial_matches = vec![0; needle.len()];
+        let mut matched = 0;
+        for (index, &byte) in needle.iter().enumerate().skip(1) {
+            while matched > 0 && byte != needle[matched] {
+                matched = partial_matches[matched - 1];
+            }
+            if byte == needle[matched] {
+                matched += 1;
+            }
+            partial_matches[index] = matched;
+        }
+
+        Self {
+            needle: needle.to_vec(),
+            partial_matches,
+        }
+    }
+
+    /// Get the index of the first match in `haystack`.
+    fn find(&self, haystack: &[u8]) -> Option<usize> {
+        let mut matched = 0;
+        let end = self.find_end(haystack, &mut matched)?;
+        Some(end - self.needle.len())
+    }
+
+    /// Continue a search into the next part of a haystack, returning the
+    /// index just past the end of the first match. `matched` starts at 0.
+    fn find_end(&self, haystack: &[u8], matched: &mut usize) -> Option<usize> {
+        if self.needle.is_empty() {
+            return Some(0);
+        }
+
+        for (index, &byte) in haystack.iter().enumerate() {
+            while *matched > 0 && byte != self.needle[*matched] {
+                *matched = self.partial_matches[*matched - 1];
+            }
+            if byte == self.needle[*matched] {
+                *matched += 1;
+            }
+            if *matched == self.needle.len() {
+                *matched = self.partial_matches[*matched - 1];
+                return Some(index + 1);
+            }
+        }
+
+        None
+    }
+}
+
+/// Builds can install read-only files, so make sure the owner can write to
+/// the file before patching it. Permissions get reset when the output is
+/// normalized afterwards.
+async fn make_writable(path: &Path, metadata: &std::fs::Metadata) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let mode = metadata.permissions().mode();
+    if mode & 0o200 == 0 {
+        let permissions = std::fs::Permissions::from_mode(mode | 0o200);
+        tokio::fs::set_permissions(path, permissions).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relocation(layer_dirs: &[&Path]) -> Relocation {
+        let layers = layer_dirs
+            .iter()
+            .enumerate()
+            .map(|(index, dir)| RelocationLayer {
+                host_path: dir.to_path_buf(),
+                store_prefix: format!("/store/{}", index).into_bytes(),
+            })
+            .collect();
+        Relocation {
+            build_prefix: Finder::new(b"/build/prefix"),
+            layers,
+            patchelf: None,
+            report: RelocationReport::default(),
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("brioche-relocate-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_finder() {
+        let finder = Finder::new(b"aab");
+        assert_eq!(finder.find(b"aaab"), Some(1));
+        assert_eq!(finder.find(b"abaabaab"), Some(2));
+        assert_eq!(finder.find(b"aaa"), None);
+        assert_eq!(finder.find(b""), None);
+
+        let finder = Finder::new(b"abab");
+        assert_eq!(finder.find(b"abaabab"), Some(3));
+        assert_eq!(finder.find(b"ababab"), Some(0));
+    }
+
+    #[test]
+    fn test_finder_across_chunks() {
+        let finder = Finder::new(b"/build/prefix");
+        let mut matched = 0;
+        assert_eq!(finder.find_end(b"foo /build/pr", &mut matched), None);
+        assert_eq!(finder.find_end(b"efix/bin", &mut matched), Some(4));
+
+        let mut matched = 0;
+        assert_eq!(finder.find_end(b"/build/pr", &mut matched), None);
+        assert_eq!(finder.find_end(b"x/build/prefix", &mut matched), Some(14));
+    }
+
+    #[test]
+    fn test_replace_references_with_providing_layer() {
+        let own_dir = temp_dir();
+        let dependency_dir = temp_dir();
+        std::fs::create_dir_all(own_dir.join("bin")).unwrap();
+        std::fs::write(own_dir.join("bin/tool"), "").unwrap();
+        std::fs::create_dir_all(dependency_dir.join("bin")).unwrap();
+        std::fs::write(dependency_dir.join("bin/sh"), "").unwrap();
+        std::fs::create_dir_all(dependency_dir.join("share/locale")).unwrap();
+
+        let relocation = relocation(&[&own_dir, &dependency_dir]);
+        let replaced = relocation
+            .replace_references(b"#!/build/prefix/bin/sh\nexec /build/prefix/bin/tool \"$@\"\n");
+        assert_eq!(
+            replaced,
+            b"#!/store/1/bin/sh\nexec /store/0/bin/tool \"$@\"\n"
+        );
+
+        // Paths that don't exist use their closest existing ancestor
+        let replaced = relocation.replace_references(b"LOCALEDIR=/build/prefix/share/locale/%s");
+        assert_eq!(replaced, b"LOCALEDIR=/store/1/share/locale/%s");
+
+        // Anything else stays in the recipe's own output
+        let replaced = relocation.replace_references(b"/build/prefix/etc/config:/build/prefix");
+        assert_eq!(replaced, b"/store/0/etc/config:/store/0");
+
+        std::fs::remove_dir_all(own_dir).unwrap();
+        std::fs::remove_dir_all(dependency_dir).unwrap();
+    }
+
+    #[test]
+    fn test_relocate_rpath() {
+        let own_dir = temp_dir();
+        let first_dependency_dir = temp_dir();
+        let second_dependency_dir = temp_dir();
+        std::fs::create_dir_all(own_dir.join("lib")).unwrap();
+        std::fs::create_dir_all(first_dependency_dir.join("lib")).unwrap();
+        std::fs::create_dir_all(second_dependency_dir.join("lib64")).unwrap();
+
+        let relocation = relocation(&[&own_dir, &first_dependency_dir, &second_dependency_dir]);
+        let rpath = relocation.relocate_rpath(b"/build/prefix/lib:$ORIGIN:/build/prefix/lib64");
+        assert_eq!(rpath, b"/store/0/lib:/store/1/lib:$ORIGIN:/store/2/lib64");
+
+        let rpath = relocation.relocate_rpath(b"/build/prefix/libexec:/build/prefix/lib");
+        assert_eq!(rpath, b"/store/0/libexec:/store/0/lib:/store/1/lib");
+
+        std::fs::remove_dir_all(own_dir).unwrap();
+        std::fs::remove_dir_all(first_dependency_dir).unwrap();
+        std::fs::remove_dir_all(second_dependency_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relocate_text_file() {
+        let own_dir = temp_dir();
+        let path = own_dir.join("script");
+        std::fs::write(&path, "#!/bin/sh\necho /build/prefix/share\n").unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+
+        let mut relocation = relocation(&[&own_dir]);
+        let scan = relocation.scan_file(&path).await.unwrap();
+        assert!(scan.is_text);
+        assert!(!scan.is_elf);
+        assert!(scan.references_build_prefix);
+
+        let relocated = relocation.relocate_file(&path, &metadata).await.unwrap();
+        assert!(matches!(relocated, FileRelocation::Relocated));
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "#!/bin/sh\necho /store/0/share\n"
+        );
+
+        std::fs::remove_dir_all(own_dir).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bake::BakeOptions,
+    recipe::{ResolvedRecipeRef, ResolvedRecipeSet},
+    sandbox::SandboxProfile,
+    state::State,
+};
+
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// The binary to run from the recipe's `bin` dir. Defaults to the
+    /// recipe's name.
+    pub bin: Option<String>,
+    pub args: Vec<OsString>,
+    /// Run the binary inside the bootstrap env instead of on the host.
+    pub sandbox: bool,
+}
+
+/// Bake a recipe if needed, then run one of its binaries with the recipe
+/// and its runtime dependencies available. Returns the exit code of the
+/// binary.
+pub async fn run(
+    state: &State,
+    recipe_set: &ResolvedRecipeSet,
+    recipe_ref: &ResolvedRecipeRef,
+    bake_options: &BakeOptions,
+    options: &RunOptions,
+) -> anyhow::Result<i32> {
+    let recipe = recipe_set.get(recipe_ref);
+    let bin = options.bin.as_deref().unwrap_or(&recipe.name);
+
+    let baked_recipe =
+        crate::bake::get_baked_recipe(state, recipe_set, recipe_ref, bake_options).await?;
+
+    // Every recipe in the closure was baked along with the recipe itself
+    let mut dependency_prefixes = vec![];
+    for dependency_ref in recipe_set.runtime_closure(&recipe.dependencies) {
+        let dependency = recipe_set.get(&dependency_ref);
+        let prefix_path = state.get_recipe_output(&dependency_ref)?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "dependency {} {} has not been baked",
+                dependency.name,
+                dependency.version
+            )
+        })?;
+        dependency_prefixes.push(prefix_path);
+    }
+
+    let bin_path = baked_recipe.prefix_path.join("bin").join(bin);
+    if !bin_path.is_file() {
+        anyhow::bail!(
+            "recipe {} {} does not have a binary named {}",
+            recipe.name,
+            recipe.version,
+            bin
+        );
+    }
+
+    if options.sandbox {
+        run_in_sandbox(
+            state,
+            &baked_recipe.prefix_path,
+            &dependency_prefixes,
+            bin,
+            &options.args,
+            bake_options.sandbox_profile,
+        )
+        .await
+    } else {
+        run_on_host(
+            &baked_recipe.prefix_path,
+            &dependency_prefixes,
+            &bin_path,
+            &options.args,
+        )
+        .await
+    }
+}
+
+async fn run_on_host(
+    prefix_path: &Path,
+    dependency_prefixes: &[PathBuf],
+    bin_path: &Path,
+    args: &[OsString],
+) -> anyhow::Result<i32> {
+    let prefixes: Vec<_> = std::iter::once(prefix_path)
+        .chain(dependency_prefixes.iter().map(|prefix| &**prefix))
+        .collect();
+
+    let mut command = tokio::process::Command::new(bin_path);
+    command.args(args);
+    command.env(
+        "PATH",
+        search_path(&prefixes, "bin", std::env::var_os("PATH"))?,
+    );
+    command.env(
+        "LD_LIBRARY_PATH",
+        search_path(&prefixes, "lib", std::env::var_os("LD_LIBRARY_PATH"))?,
+    );
+
+    // Don't leave the process running if brioche gets cancelled
+    command.kill_on_drop(true);
+
+    let mut child = command.spawn()?;
+    let exit_status = crate::bootstrap_env::wait_interactive(child.wait()).await?;
+
+    crate::bootstrap_env::host_exit_code(exit_status)
+}
+
+async fn run_in_sandbox(
+    state: &State,
+    prefix_path: &Path,
+    dependency_prefixes: &[PathBuf],
+    bin: &str,
+    args: &[OsString],
+    sandbox_profile: SandboxProfile,
+) -> anyhow::Result<i32> {
+    let mut bootstrap_env = crate::bootstrap_env::BootstrapEnv::new(state).await?;
+    bootstrap_env.set_sandbox_profile(sandbox_profile);
+
+    // Layers added later take precedence, so the recipe itself goes last
+    for dependency_prefix in dependency_prefixes.iter().rev() {
+        bootstrap_env
+            .add_dependency_layer(dependency_prefix)
+            .await?;
+    }
+    bootstrap_env.add_dependency_layer(prefix_path).await?;
+
+    let recipe_prefix = bootstrap_env.recipe_prefix_path();
+    let mut command =
+        crate::bootstrap_env::Command::new(recipe_prefix.container_path.join("bin").join(bin));
+    for arg in args {
+        command.arg(arg);
+    }
+    command.current_dir("/");
+    command.env("LD_LIBRARY_PATH", recipe_prefix.container_path.join("lib"));
+    command.inherit_stdio();
+
+    let child = bootstrap_env.spawn(&command)?;
+    let exit_status = child.wait_interactive().await?;
+
+    Ok(crate::bootstrap_env::exit_code(&exit_status))
+}
+
+/// Build a search path (like `PATH`) from a subdirectory of each prefix
+/// that exists, followed by the existing value from the host.
+pub fn search_path(
+    prefixes: &[&Path],
+    subdir: &str,
+    host_value: Option<OsString>,
+) -> anyhow::Result<OsString> {
+    let prefix_dirs = prefixes
+        .iter()
+        .map(|prefix| prefix.join(subdir))
+        .filter(|dir| dir.is_dir());
+    let host_dirs = host_value
+        .iter()
+        .flat_map(std::env::split_paths)
+        .collect::<Vec<_>>();
+
+    let search_path = std::env::join_paths(prefix_dirs.chain(host_dirs))?;
+    Ok(search_path)
+}
@@ -0,0 +1,770 @@
+use std::path::Path;
+
+use nix::{errno::Errno, libc, mount::MsFlags};
+
+/// How much of the host the build sandbox is allowed to see and do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SandboxProfile {
+    /// Minimal `/dev`, a private `/proc`, read-only `/sys`, and reduced
+    /// capabilities and syscalls
+    #[default]
+    Hardened,
+    /// Host `/dev`, `/proc`, and `/sys`, with every capability and syscall
+    /// available to root in a user namespace
+    Permissive,
+}
+
+/// The host devices available in `/dev` in hardened builds.
+const HARDENED_DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom", "tty"];
+
+/// Mount `/proc`, `/sys`, and `/dev` under `target_dir` for a hardened
+/// build. Has to be called from within the build's user, mount, and PID
+/// namespaces.
+pub fn mount_hardened_system_dirs(target_dir: &Path) -> anyhow::Result<()> {
+    // A fresh procfs only shows the processes in the build's PID namespace
+    nix::mount::mount(
+        Some("proc"),
+        &target_dir.join("proc"),
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None::<&str>,
+    )
+    .map_err(|error| anyhow::anyhow!("failed to mount /proc: {}", error))?;
+
+    // Host `/sys` always has submounts, which are locked in a user namespace
+    // and can only be bound along with it. Only the top mount is made
+    // read-only: the submounts (e.g. cgroups) are owned by the host's root,
+    // so they're not writable from the sandbox anyway
+    let sys_dir = target_dir.join("sys");
+    nix::mount::mount(
+        Some("/sys"),
+        &sys_dir,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )
+    .map_err(|error| anyhow::anyhow!("failed to bind /sys: {}", error))?;
+    remount_readonly(
+        &sys_dir,
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+    )
+    .map_err(|error| anyhow::anyhow!("failed to make /sys read-only: {}", error))?;
+
+    let dev_dir = target_dir.join("dev");
+    nix::mount::mount(
+        Some("tmpfs"),
+        &dev_dir,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("mode=0755"),
+    )
+    .map_err(|error| anyhow::anyhow!("failed to mount /dev: {}", error))?;
+
+    // Device nodes can't be created in a user namespace, so bind mount
+    // the host's devices instead
+    for device in HARDENED_DEVICES {
+        let device_path = dev_dir.join(device);
+        std::fs::File::create(&device_path)?;
+        nix::mount::mount(
+            Some(&Path::new("/dev").join(device)),
+            &device_path,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .map_err(|error| anyhow::anyhow!("failed to bind /dev/{}: {}", device, error))?;
+    }
+
+    // A new devpts instance, so the build can't reach the host's terminals
+    // (other than its own)
+    let pts_dir = dev_dir.join("pts");
+    std::fs::create_dir(&pts_dir)?;
+    nix::mount::mount(
+        Some("devpts"),
+        &pts_dir,
+        Some("devpts"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+        Some("newinstance,ptmxmode=0666,mode=0620"),
+    )
+    .map_err(|error| anyhow::anyhow!("failed to mount /dev/pts: {}", error))?;
+    std::os::unix::fs::symlink("pts/ptmx", dev_dir.join("ptmx"))?;
+
+    let shm_dir = dev_dir.join("shm");
+    std::fs::create_dir(&shm_dir)?;
+    nix::mount::mount(
+        Some("tmpfs"),
+        &shm_dir,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some("mode=1777"),
+    )
+    .map_err(|error| anyhow::anyhow!("failed to mount /dev/shm: {}", error))?;
+
+    std::os::unix::fs::symlink("/proc/self/fd", dev_dir.join("fd"))?;
+    std::os::unix::fs::symlink("/proc/self/fd/0", dev_dir.join("stdin"))?;
+    std::os::unix::fs::symlink("/proc/self/fd/1", dev_dir.join("stdout"))?;
+    std::os::unix::fs::symlink("/proc/self/fd/2", dev_dir.join("stderr"))?;
+
+    Ok(())
+}
+
+/// Make a bind mount read-only, adding `extra_flags` to the flags a user
+/// namespace locks (like `nosuid`).
+pub fn remount_readonly(path: &Path, extra_flags: MsFlags) -> nix::Result<()> {
+    use nix::sys::statvfs::FsFlags;
+
+    let current_flags = nix::sys::statvfs::statvfs(path)?.flags();
+    let mut flags = MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY | extra_flags;
+    let kept_flags = [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ];
+    for (fs_flag, mount_flag) in kept_flags {
+        if current_flags.contains(fs_flag) {
+            flags |= mount_flag;
+        }
+    }
+
+    nix::mount::mount(None::<&str>, path, None::<&str>, flags, None::<&str>)
+}
+
+/// Capabilities kept by hardened builds: enough to act as root over the
+/// files in the sandbox and to manage their own processes.
+const KEPT_CAPABILITIES: &[libc::c_ulong] = &[
+    0,  // CAP_CHOWN
+    1,  // CAP_DAC_OVERRIDE
+    3,  // CAP_FOWNER
+    4,  // CAP_FSETID
+    5,  // CAP_KILL
+    6,  // CAP_SETGID
+    7,  // CAP_SETUID
+    10, // CAP_NET_BIND_SERVICE
+];
+
+/// Drop every capability not in `KEPT_CAPABILITIES` from the bounding set.
+/// The current process keeps its capabilities (so the sandbox can still be
+/// set up), but they're gone once the build command gets executed.
+pub fn drop_capabilities() -> nix::Result<()> {
+    for capability in 0..64 {
+        if KEPT_CAPABILITIES.contains(&capability) {
+            continue;
+        }
+
+        // SAFETY: `PR_CAPBSET_DROP` doesn't take any pointers
+        let result = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, capability, 0, 0, 0) };
+        if result != 0 {
+            match Errno::last() {
+                // Past the last capability the kernel knows about
+                Errno::EINVAL => {
+                    break;
+                }
+                errno => {
+                    return Err(errno);
+                }
+            }
+        }
+    }
+
+    // Also stop executables from gaining privileges (e.g. with file
+    // capabilities) from here on
+    // SAFETY: `PR_SET_NO_NEW_PRIVS` doesn't take any pointers
+    let result = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    Errno::result(result)?;
+
+    Ok(())
+}
+
+// Classic BPF opcodes, from `linux/filter.h`
+const BPF_LD_W_ABS: u16 = 0x20; // BPF_LD | BPF_W | BPF_ABS
+const BPF_JMP_JEQ_K: u16 = 0x15; // BPF_JMP | BPF_JEQ | BPF_K
+const BPF_JMP_JGE_K: u16 = 0x35; // BPF_JMP | BPF_JGE | BPF_K
+const BPF_JMP_JSET_K: u16 = 0x45; // BPF_JMP | BPF_JSET | BPF_K
+const BPF_RET_K: u16 = 0x06; // BPF_RET | BPF_K
+
+// From `linux/seccomp.h`
+const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+// Offsets of fields in `struct seccomp_data`. The first argument is a
+// 64-bit value, and its low half comes first on little-endian targets
+const SECCOMP_DATA_NR_OFFSET: u32 = 0;
+const SECCOMP_DATA_ARCH_OFFSET: u32 = 4;
+const SECCOMP_DATA_ARG0_LOW_OFFSET: u32 = 16;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH_NATIVE: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH_NATIVE: u32 = 0xc000_00b7;
+
+/// Syscalls on x86-64 with this bit set use the x32 ABI, which isn't used by
+/// the bootstrap environment and would bypass the syscall numbers below.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// `clone` flags that create new namespaces. `CLONE_NEWTIME` is left out,
+/// since `clone` doesn't support it (the bit is part of the exit signal).
+const CLONE_NAMESPACE_FLAGS: u32 = (libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET) as u32;
+
+/// Syscalls that fail with `EPERM` in hardened builds (`clone` is filtered
+/// separately).
+fn denied_syscalls() -> Vec<libc::c_long> {
+    #[allow(unused_mut)]
+    let mut syscalls = vec![
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_kexec_load,
+        libc::SYS_reboot,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_acct,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_clock_adjtime,
+        libc::SYS_adjtimex,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_syslog,
+        libc::SYS_quotactl,
+        libc::SYS_vhangup,
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    syscalls.extend([libc::SYS_iopl, libc::SYS_ioperm]);
+
+    syscalls
+}
+
+/// Where a filter instruction can jump to. Classic BPF can only jump
+/// forwards, so every label comes after the instructions that jump to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Label {
+    CheckCloneFlags,
+    Deny,
+    Unsupported,
+    KillProcess,
+}
+
+enum FilterInstruction {
+    Statement {
+        code: u16,
+        k: u32,
+    },
+    /// Jump to `jt` if the condition is true or `jf` if it's false, where
+    /// `None` means the next instruction
+    Jump {
+        code: u16,
+        k: u32,
+        jt: Option<Label>,
+        jf: Option<Label>,
+    },
+    /// Mark the position of a label (not an actual instruction)
+    Define(Label),
+}
+
+/// Build the seccomp filter for hardened builds. Denied syscalls fail with
+/// `EPERM`, `clone3` with `ENOSYS` (so libc falls back to `clone`), and
+/// syscalls from other ABIs kill the build.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn seccomp_filter() -> Vec<libc::sock_filter> {
+    use FilterInstruction::*;
+
+    let mut instructions = vec![
+        Statement {
+            code: BPF_LD_W_ABS,
+            k: SECCOMP_DATA_ARCH_OFFSET,
+        },
+        Jump {
+            code: BPF_JMP_JEQ_K,
+            k: AUDIT_ARCH_NATIVE,
+            jt: None,
+            jf: Some(Label::KillProcess),
+        },
+        Statement {
+            code: BPF_LD_W_ABS,
+            k: SECCOMP_DATA_NR_OFFSET,
+        },
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    instructions.push(Jump {
+        code: BPF_JMP_JGE_K,
+        k: X32_SYSCALL_BIT,
+        jt: Some(Label::Deny),
+        jf: None,
+    });
+
+    for syscall in denied_syscalls() {
+        instructions.push(Jump {
+            code: BPF_JMP_JEQ_K,
+            k: syscall as u32,
+            jt: Some(Label::Deny),
+            jf: None,
+        });
+    }
+
+    instructions.extend([
+        Jump {
+            code: BPF_JMP_JEQ_K,
+            k: libc::SYS_clone3 as u32,
+            jt: Some(Label::Unsupported),
+            jf: None,
+        },
+        Jump {
+            code: BPF_JMP_JEQ_K,
+            k: libc::SYS_clone as u32,
+            jt: Some(Label::CheckCloneFlags),
+            jf: None,
+        },
+        Statement {
+            code: BPF_RET_K,
+            k: SECCOMP_RET_ALLOW,
+        },
+        Define(Label::CheckCloneFlags),
+        Statement {
+            code: BPF_LD_W_ABS,
+            k: SECCOMP_DATA_ARG0_LOW_OFFSET,
+        },
+        Jump {
+            code: BPF_JMP_JSET_K,
+            k: CLONE_NAMESPACE_FLAGS,
+            jt: Some(Label::Deny),
+            jf: None,
+        },
+        Statement {
+            code: BPF_RET_K,
+            k: SECCOMP_RET_ALLOW,
+        },
+        Define(Label::Deny),
+        Statement {
+            code: BPF_RET_K,
+            k: SECCOMP_RET_ERRNO | libc::EPERM as u32,
+        },
+        Define(Label::Unsupported),
+        Statement {
+            code: BPF_RET_K,
+            k: SECCOMP_RET_ERRNO | libc::ENOSYS as u32,
+        },
+        Define(Label::KillProcess),
+        Statement {
+            code: BPF_RET_K,
+            k: SECCOMP_RET_KILL_PROCESS,
+        },
+    ]);
+
+    assemble_filter(&instructions)
+}
+
+/// Resolve the labels in a filter to jump offsets, which are relative to
+/// the instruction after the jump.
+fn assemble_filter(instructions: &[FilterInstruction]) -> Vec<libc::sock_filter> {
+    let mut label_positions = vec![];
+    let mut position: usize = 0;
+    for instruction in instructions {
+        match instruction {
+            FilterInstruction::Define(label) => {
+                label_positions.push((*label, position));
+            }
+            FilterInstruction::Statement { .. } | FilterInstruction::Jump { .. } => {
+                position += 1;
+            }
+        }
+    }
+
+    let jump_offset = |position: usize, label: Option<Label>| -> u8 {
+        let label = match label {
+            Some(label) => label,
+            None => {
+                return 0;
+            }
+        };
+        let (_, label_position) = label_positions
+            .iter()
+            .find(|(other_label, _)| *other_label == label)
+            .expect("seccomp filter jumps to a missing label");
+        let offset = label_position
+            .checked_sub(position + 1)
+            .expect("seccomp filter jumps backwards");
+        offset
+            .try_into()
+            .expect("seccomp filter has too many instructions")
+    };
+
+    let mut filter = vec![];
+    for instruction in instructions {
+        match *instruction {
+            FilterInstruction::Statement { code, k } => {
+                filter.push(libc::sock_filter {
+                    code,
+                    jt: 0,
+                    jf: 0,
+                    k,
+                });
+            }
+            FilterInstruction::Jump { code, k, jt, jf } => {
+                let position = filter.len();
+                filter.push(libc::sock_filter {
+                    code,
+                    jt: jump_offset(position, jt),
+                    jf: jump_offset(position, jf),
+                    k,
+                });
+            }
+            FilterInstruction::Define(_) => {}
+        }
+    }
+
+    filter
+}
+
+/// Install the seccomp filter from `seccomp_filter`. The filter is inherited
+/// by every process the build starts.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn install_seccomp_filter() -> nix::Result<()> {
+    let mut filter = seccomp_filter();
+    let program = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_mut_ptr(),
+    };
+
+    // SAFETY: `program` points to `filter`, which outlives the call (the
+    // kernel copies the filter)
+    let result = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            SECCOMP_MODE_FILTER,
+            &program as *const libc::sock_fprog,
+            0,
+            0,
+        )
+    };
+    Errno::result(result)?;
+
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn install_seccomp_filter() -> nix::Result<()> {
+    Err(Errno::ENOTSUP)
+}
+
+#[cfg(all(test, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+
+    /// Run a seccomp filter the way the kernel would, for a syscall with
+    /// the given first argument.
+    fn run_filter(filter: &[libc::sock_filter], arch: u32, nr: u32, arg0: u64) -> u32 {
+        let mut data = [0u8; 64];
+        data[0..4].copy_from_slice(&nr.to_le_bytes());
+        data[4..8].copy_from_slice(&arch.to_le_bytes());
+        data[16..24].copy_from_slice(&arg0.to_le_bytes());
+
+        let mut accumulator = 0;
+        let mut pc = 0;
+        loop {
+            let instruction = filter[pc];
+            pc += 1;
+            let k = instruction.k;
+            let condition = match instruction.code {
+                BPF_LD_W_ABS => {
+                    let offset = k as usize;
+                    accumulator = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+                    continue;
+                }
+                BPF_RET_K => {
+                    return k;
+                }
+                BPF_JMP_JEQ_K => accumulator == k,
+                BPF_JMP_JGE_K => accumulator >= k,
+                BPF_JMP_JSET_K => accumulator & k != 0,
+                code => panic!("unexpected instruction {:#x}", code),
+            };
+            let offset = if condition {
+                instruction.jt
+            } else {
+                instruction.jf
+            };
+            pc += offset as usize;
+        }
+    }
+
+    fn run_native_filter(nr: libc::c_long, arg0: u64) -> u32 {
+        run_filter(&seccomp_filter(), AUDIT_ARCH_NATIVE, nr as u32, arg0)
+    }
+
+    const DENY: u32 = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    #[test]
+    fn test_filter_jumps_stay_in_bounds() {
+        let filter = seccomp_filter();
+        for (position, instruction) in filter.iter().enumerate() {
+            if instruction.code & 0x07 == 0x05 {
+                let last = position + 1 + instruction.jt.max(instruction.jf) as usize;
+                assert!(last < filter.len(), "jump at {} is out of bounds", position);
+            }
+        }
+        assert_eq!(filter.last().unwrap().code, BPF_RET_K);
+    }
+
+    #[test]
+    fn test_filter_denies_listed_syscalls() {
+        for syscall in denied_syscalls() {
+            assert_eq!(run_native_filter(syscall, 0), DENY, "syscall {}", syscall);
+        }
+    }
+
+    #[test]
+    fn test_filter_allows_other_syscalls() {
+        for syscall in [
+            libc::SYS_read,
+            libc::SYS_write,
+            libc::SYS_execve,
+            libc::SYS_chroot,
+        ] {
+            assert_eq!(
+                run_native_filter(syscall, 0),
+                SECCOMP_RET_ALLOW,
+                "syscall {}",
+                syscall
+            );
+        }
+    }
+
+    #[test]
+    fn test_filter_checks_clone_flags() {
+        let fork_flags = libc::SIGCHLD as u64;
+        let thread_flags = (libc::CLONE_VM | libc::CLONE_THREAD | libc::CLONE_SIGHAND) as u64;
+        assert_eq!(
+            run_native_filter(libc::SYS_clone, fork_flags),
+            SECCOMP_RET_ALLOW
+        );
+        assert_eq!(
+            run_native_filter(libc::SYS_clone, thread_flags),
+            SECCOMP_RET_ALLOW
+        );
+
+        for flag in [
+            libc::CLONE_NEWUSER,
+            libc::CLONE_NEWNS,
+            libc::CLONE_NEWPID,
+            libc::CLONE_NEWNET,
+        ] {
+            let flags = fork_flags | flag as u64;
+            assert_eq!(run_native_filter(libc::SYS_clone, flags), DENY);
+        }
+
+        assert_eq!(
+            run_native_filter(libc::SYS_clone3, 0),
+            SECCOMP_RET_ERRNO | libc::ENOSYS as u32
+        );
+    }
+
+    #[test]
+    fn test_filter_kills_other_architectures() {
+        let filter = seccomp_filter();
+        let result = run_filter(&filter, 0x4000_0003, libc::SYS_read as u32, 0);
+        assert_eq!(result, SECCOMP_RET_KILL_PROCESS);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_filter_denies_x32_syscalls() {
+        let nr = X32_SYSCALL_BIT | libc::SYS_read as u32;
+        let result = run_filter(&seccomp_filter(), AUDIT_ARCH_NATIVE, nr, 0);
+        assert_eq!(result, DENY);
+    }
+
+    /// Set up a hardened sandbox in a forked child, then check that
+    /// processes still start and denied syscalls fail.
+    #[test]
+    fn test_hardened_sandbox() {
+        use nix::{
+            sys::wait::{waitpid, WaitStatus},
+            unistd::ForkResult,
+        };
+
+        let target_dir =
+            std::env::temp_dir().join(format!("brioche-sandbox-test-{}", uuid::Uuid::new_v4()));
+        for dir in ["proc", "sys", "dev"] {
+            std::fs::create_dir_all(target_dir.join(dir)).unwrap();
+        }
+        let uid_map = format!("0 {} 1", nix::unistd::getuid());
+        let gid_map = format!("0 {} 1", nix::unistd::getgid());
+
+        // SAFETY: glibc makes allocating in the child safe, and the child
+        // only exits with `_exit`
+        let fork_result = unsafe { nix::unistd::fork().unwrap() };
+        let child_pid = match fork_result {
+            ForkResult::Child => {
+                let exit_code = match run_hardened_sandbox(&target_dir, &uid_map, &gid_map) {
+                    Ok(exit_code) => exit_code,
+                    Err(error) => {
+                        let message = format!("{}\n", error);
+                        let _ = nix::unistd::write(2, message.as_bytes());
+                        1
+                    }
+                };
+
+                // SAFETY: `_exit` is always safe to call
+                unsafe { libc::_exit(exit_code) };
+            }
+            ForkResult::Parent { child } => child,
+        };
+
+        let status = waitpid(child_pid, None).unwrap();
+        let _ = std::fs::remove_dir_all(&target_dir);
+        match status {
+            WaitStatus::Exited(_, 0) => {}
+            WaitStatus::Exited(_, SKIPPED) => {
+                eprintln!("skipped: user namespaces aren't available");
+            }
+            status => panic!("hardened sandbox checks failed: {:?}", status),
+        }
+    }
+
+    const SKIPPED: i32 = 77;
+
+    /// Enter new namespaces and run `check_hardened_sandbox` as PID 1 of the
+    /// new PID namespace, returning the exit code for the test.
+    fn run_hardened_sandbox(
+        target_dir: &Path,
+        uid_map: &str,
+        gid_map: &str,
+    ) -> anyhow::Result<i32> {
+        use nix::{
+            sched::CloneFlags,
+            sys::wait::{waitpid, WaitStatus},
+            unistd::ForkResult,
+        };
+
+        let namespaces =
+            CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWPID;
+        if nix::sched::unshare(namespaces).is_err() {
+            return Ok(SKIPPED);
+        }
+        std::fs::write("/proc/self/setgroups", "deny")?;
+        std::fs::write("/proc/self/uid_map", uid_map)?;
+        std::fs::write("/proc/self/gid_map", gid_map)?;
+
+        // SAFETY: See above
+        match unsafe { nix::unistd::fork()? } {
+            ForkResult::Child => {
+                let exit_code = match check_hardened_sandbox(target_dir) {
+                    Ok(()) => 0,
+                    Err(error) => {
+                        let message = format!("{:#}\n", error);
+                        let _ = nix::unistd::write(2, message.as_bytes());
+                        1
+                    }
+                };
+
+                // SAFETY: `_exit` is always safe to call
+                unsafe { libc::_exit(exit_code) };
+            }
+            ForkResult::Parent { child } => match waitpid(child, None)? {
+                WaitStatus::Exited(_, exit_code) => Ok(exit_code),
+                status => anyhow::bail!("sandbox exited unexpectedly: {:?}", status),
+            },
+        }
+    }
+
+    fn check_hardened_sandbox(target_dir: &Path) -> anyhow::Result<()> {
+        mount_hardened_system_dirs(target_dir)?;
+
+        // Only the processes in the new PID namespace are visible
+        anyhow::ensure!(
+            target_dir.join("proc").join("1").exists(),
+            "PID 1 not found in /proc"
+        );
+        let num_processes = std::fs::read_dir(target_dir.join("proc"))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().parse::<u32>().is_ok())
+            .count();
+        anyhow::ensure!(
+            num_processes == 1,
+            "{} processes are visible in /proc",
+            num_processes
+        );
+
+        match std::fs::File::create(target_dir.join("sys").join("brioche-test")) {
+            Err(error) if error.raw_os_error() == Some(libc::EROFS) => {}
+            result => anyhow::bail!("/sys is not read-only: {:?}", result),
+        }
+
+        std::fs::write(target_dir.join("dev").join("null"), "test")?;
+        anyhow::ensure!(
+            !target_dir.join("dev").join("sda").exists(),
+            "host devices are visible in /dev"
+        );
+
+        drop_capabilities()?;
+        install_seccomp_filter()?;
+
+        // Starting processes still works (libc falls back from `clone3`)
+        let status = std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg("exit 3")
+            .status()?;
+        anyhow::ensure!(status.code() == Some(3), "/bin/sh failed: {}", status);
+
+        let result = nix::sched::unshare(nix::sched::CloneFlags::CLONE_NEWNS);
+        anyhow::ensure!(result == Err(Errno::EPERM), "unshare returned {:?}", result);
+
+        let result = nix::mount::mount(
+            Some("tmpfs"),
+            &target_dir.join("dev"),
+            Some("tmpfs"),
+            MsFlags::empty(),
+            None::<&str>,
+        );
+        anyhow::ensure!(result == Err(Errno::EPERM), "mount returned {:?}", result);
+
+        // SAFETY: If the filter lets this through, the new child exits
+        // immediately
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_clone,
+                (libc::CLONE_NEWUSER | libc::SIGCHLD) as libc::c_ulong,
+                0,
+                0,
+                0,
+                0,
+            )
+        };
+        if result == 0 {
+            // SAFETY: `_exit` is always safe to call
+            unsafe { libc::_exit(0) };
+        }
+        anyhow::ensure!(
+            result == -1 && Errno::last() == Errno::EPERM,
+            "clone with CLONE_NEWUSER returned {} ({})",
+            result,
+            Errno::last()
+        );
+
+        Ok(())
+    }
+}
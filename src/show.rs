@@ -0,0 +1,141 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    format::{format_duration, format_size},
+    recipe::{ResolvedRecipe, ResolvedRecipeRef},
+    state::State,
+};
+
+/// Print the stored metadata for a recipe. If the recipe hasn't been baked,
+/// `resolved_recipe` is shown instead (when available).
+pub async fn show(
+    state: &State,
+    recipe_ref: &ResolvedRecipeRef,
+    resolved_recipe: Option<&ResolvedRecipe>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let stored_recipe = state.get_recipe_metadata(recipe_ref).await?;
+    let build_metadata = state.get_build_metadata(recipe_ref).await?;
+    let prefix_path = state.get_recipe_output(recipe_ref)?;
+
+    let recipe = match (&stored_recipe, resolved_recipe) {
+        (Some(recipe), _) => recipe,
+        (None, Some(recipe)) => recipe,
+        (None, None) => {
+            anyhow::bail!("no metadata found for recipe {}", recipe_ref);
+        }
+    };
+
+    if json {
+        let value = serde_json::json!({
+            "hash": recipe_ref,
+            "recipe": recipe,
+            "build": build_metadata,
+            "prefix": prefix_path,
+        });
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        return Ok(());
+    }
+
+    println!("{} {}", recipe.name, recipe.version);
+    println!("  Hash: {}", recipe_ref);
+    println!("  Source: {}", recipe.source);
+
+    print_dependencies(state, "Dependencies", &recipe.dependencies).await?;
+    print_dependencies(state, "Build dependencies", &recipe.build_dependencies).await?;
+
+    match (&build_metadata, &prefix_path) {
+        (Some(build_metadata), Some(prefix_path)) => {
+            let baked_ago = build_metadata.baked_at.elapsed().unwrap_or_default();
+            println!("  Baked: {} ago", format_duration(baked_ago));
+            if let Some(content_hash) = build_metadata.content_hash {
+                println!("  Content hash: {}", content_hash);
+            }
+            if let Some(aux) = &build_metadata.aux {
+                println!(
+                    "  Build time: {} ({} CPU)",
+                    format_duration(aux.wall_time),
+                    format_duration(aux.cpu_time)
+                );
+                if let Some(peak_memory_bytes) = aux.cgroup_peak_memory_bytes {
+                    println!("  Peak memory: {}", format_size(peak_memory_bytes));
+                }
+                println!(
+                    "  Max RSS of a single process: {}",
+                    format_size(aux.max_process_rss_bytes)
+                );
+                println!(
+                    "  Output: {} in {} files",
+                    format_size(aux.output_size_bytes),
+                    aux.output_file_count
+                );
+                println!(
+                    "  Log: {} lines on stdout, {} lines on stderr",
+                    aux.lines_stdout, aux.lines_stderr
+                );
+                println!(
+                    "  Baked with: brioche {} on {}",
+                    aux.brioche_version, aux.host_triple
+                );
+            }
+            println!("  Prefix: {}", prefix_path.display());
+        }
+        (None, Some(prefix_path)) => {
+            println!("  Prefix: {}", prefix_path.display());
+        }
+        (_, None) => {
+            println!("  Baked: no");
+        }
+    }
+
+    if !recipe.build.env_vars.is_empty() {
+        println!("  Env vars:");
+        let mut env_vars: Vec<_> = recipe.build.env_vars.iter().collect();
+        env_vars.sort();
+        for (name, value) in env_vars {
+            println!("    {}={}", name, value);
+        }
+    }
+
+    if let Some(timeout) = recipe.build.timeout {
+        println!(
+            "  Timeout: {}",
+            format_duration(std::time::Duration::from_secs(timeout.into()))
+        );
+    }
+
+    println!("  Build script ({}):", recipe.build.shell);
+    for line in recipe.build.script.trim_matches('\n').lines() {
+        println!("    {}", line);
+    }
+
+    Ok(())
+}
+
+async fn print_dependencies(
+    state: &State,
+    label: &str,
+    dependencies: &BTreeSet<ResolvedRecipeRef>,
+) -> anyhow::Result<()> {
+    if dependencies.is_empty() {
+        println!("  {}: none", label);
+        return Ok(());
+    }
+
+    println!("  {}:", label);
+    for dependency_ref in dependencies {
+        match state.get_recipe_metadata(dependency_ref).await? {
+            Some(dependency) => {
+                println!(
+                    "    {} {} ({})",
+                    dependency.name, dependency.version, dependency_ref
+                );
+            }
+            None => {
+                println!("    {}", dependency_ref);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -1,8 +1,10 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env,
     io::SeekFrom,
     path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context as _;
@@ -15,26 +17,41 @@ use tokio::{
 use url::Url;
 use uuid::Uuid;
 
-use crate::{hash::Hash, recipe::ResolvedRecipeRef};
+use crate::{
+    event::{Event, EventSink, MessageLevel},
+    hash::Hash,
+    recipe::{ResolvedRecipe, ResolvedRecipeRef},
+};
+
+/// The minimum time between download progress events.
+const DOWNLOAD_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
 
 #[derive(Debug)]
 pub struct State {
-    project_dirs: directories::ProjectDirs,
     lockfile: Lockfile,
+    events: Arc<dyn EventSink>,
+    pub recipes_dir: PathBuf,
     pub checkouts_dir: PathBuf,
     pub downloads_dir: PathBuf,
+    pub unpack_dir: PathBuf,
+    pub gc_roots_dir: PathBuf,
+    pub profile_dir: PathBuf,
     pub temp_checkouts_dir: PathBuf,
     pub temp_downloads_dir: PathBuf,
+    pub temp_work_dirs_dir: PathBuf,
 }
 
 impl State {
-    pub async fn new() -> anyhow::Result<Self> {
+    pub async fn new(events: Arc<dyn EventSink>) -> anyhow::Result<Self> {
         let project_dirs = directories::ProjectDirs::from("dev.brioche", "Brioche", "brioche")
             .context("home directory not found")?;
 
         let data_dir = project_dirs.data_dir();
         fs::create_dir_all(&data_dir).await?;
 
+        let recipes_dir = data_dir.join("recipes");
+        fs::create_dir_all(&recipes_dir).await?;
+
         let checkouts_dir = data_dir.join("checkouts");
         fs::create_dir_all(&checkouts_dir).await?;
 
@@ -47,16 +64,32 @@ impl State {
         let temp_downloads_dir = downloads_dir.join("_temp");
         fs::create_dir_all(&temp_downloads_dir).await?;
 
+        let unpack_dir = data_dir.join("unpack");
+        fs::create_dir_all(&unpack_dir).await?;
+
+        let gc_roots_dir = data_dir.join("gcroots");
+        fs::create_dir_all(&gc_roots_dir).await?;
+
+        let profile_dir = data_dir.join("profile");
+        fs::create_dir_all(&profile_dir).await?;
+
+        let temp_work_dirs_dir = env::temp_dir().join("brioche").join("work-dir");
+
         let lockfile_path = data_dir.join("lockfile.json");
         let lockfile = Lockfile::open(lockfile_path).await?;
 
         Ok(Self {
-            project_dirs,
             lockfile,
+            events,
+            recipes_dir,
             checkouts_dir,
             downloads_dir,
+            unpack_dir,
+            gc_roots_dir,
+            profile_dir,
             temp_checkouts_dir,
             temp_downloads_dir,
+            temp_work_dirs_dir,
         })
     }
 
@@ -65,18 +98,36 @@ impl State {
         Ok(result)
     }
 
-    pub async fn new_temp_work_dir(&self) -> anyhow::Result<PathBuf> {
+    pub fn emit(&self, event: Event) {
+        self.events.emit(event);
+    }
+
+    /// Get the event sink, for emitting events from blocking tasks.
+    pub fn events(&self) -> Arc<dyn EventSink> {
+        self.events.clone()
+    }
+
+    pub async fn new_temp_work_dir(&self) -> anyhow::Result<TempWorkDir> {
+        use std::os::unix::io::AsRawFd as _;
+
         let uuid = uuid::Uuid::new_v4();
-        let temp_dir = env::temp_dir();
-        let work_dir = temp_dir
-            .join("brioche")
-            .join("work-dir")
-            .join(uuid.to_string())
-            .join("work");
+        let dir = self.temp_work_dirs_dir.join(uuid.to_string());
+
+        // Lock the work dir before creating it, so GC never sees it unlocked
+        // while it's in use
+        fs::create_dir_all(&self.temp_work_dirs_dir).await?;
+        let lock_path = work_dir_lock_path(&dir);
+        let lock_file = std::fs::File::create(&lock_path)?;
+        nix::fcntl::flock(lock_file.as_raw_fd(), nix::fcntl::FlockArg::LockExclusive)?;
 
+        let work_dir = dir.join("work");
         fs::create_dir_all(&work_dir).await?;
 
-        Ok(work_dir)
+        Ok(TempWorkDir {
+            path: work_dir,
+            lock_path,
+            _lock_file: lock_file,
+        })
     }
 
     pub async fn get_existing_content_file(&self, req: &ContentRequest) -> Option<ContentFile> {
@@ -113,22 +164,55 @@ impl State {
         response.error_for_status_ref()?;
         let mut file_hash = sha2::Sha256::new();
 
-        let mut response_body_stream = response.bytes_stream();
-        while let Some(chunk) = response_body_stream.next().await {
-            let chunk = chunk?;
-            download_file.write_all(&chunk).await?;
-            file_hash.update(&chunk);
-        }
+        let total_bytes = response.content_length();
+        self.emit(Event::DownloadStarted {
+            url: req.url.clone(),
+            total_bytes,
+        });
+
+        let download_result: anyhow::Result<Hash> = async {
+            let mut downloaded_bytes = 0;
+            let mut last_progress = Instant::now();
+            let mut response_body_stream = response.bytes_stream();
+            while let Some(chunk) = response_body_stream.next().await {
+                let chunk = chunk?;
+                download_file.write_all(&chunk).await?;
+                file_hash.update(&chunk);
+
+                downloaded_bytes += chunk.len() as u64;
+                if last_progress.elapsed() >= DOWNLOAD_PROGRESS_INTERVAL {
+                    last_progress = Instant::now();
+                    self.emit(Event::DownloadProgress {
+                        url: req.url.clone(),
+                        downloaded_bytes,
+                        total_bytes,
+                    });
+                }
+            }
 
-        let downloaded_hash = Hash::from_digest(file_hash);
-        if let Some(expected_hash) = req.content_hash {
-            if expected_hash != downloaded_hash {
-                anyhow::bail!(
-                    "File hash did not match for {} (expected {}, got {})",
-                    req.url,
-                    expected_hash,
-                    downloaded_hash,
-                );
+            let downloaded_hash = Hash::from_digest(file_hash);
+            if let Some(expected_hash) = req.content_hash {
+                if expected_hash != downloaded_hash {
+                    anyhow::bail!(
+                        "File hash did not match for {} (expected {}, got {})",
+                        req.url,
+                        expected_hash,
+                        downloaded_hash,
+                    );
+                }
+            };
+
+            Ok(downloaded_hash)
+        }
+        .await;
+        let downloaded_hash = match download_result {
+            Ok(downloaded_hash) => downloaded_hash,
+            Err(error) => {
+                self.emit(Event::DownloadFailed {
+                    url: req.url.clone(),
+                    error: format!("{:#}", error),
+                });
+                return Err(error);
             }
         };
 
@@ -136,19 +220,26 @@ impl State {
         let rename_result = fs::rename(&temp_file_path, &final_file_path).await;
         match rename_result {
             Ok(()) => {
-                println!(
-                    "Downloaded URL {} -> {}",
-                    req.url,
-                    final_file_path.display()
-                );
+                self.emit(Event::DownloadFinished {
+                    url: req.url.clone(),
+                    content_hash: downloaded_hash,
+                    path: final_file_path,
+                });
             }
             Err(error) => {
-                eprintln!(
-                    "Downloaded URL {} -> {} (failed to rename: {})",
-                    req.url,
-                    temp_file_path.display(),
-                    error
-                );
+                self.emit(Event::Message {
+                    level: MessageLevel::Warning,
+                    message: format!(
+                        "failed to move download of {} into place: {}",
+                        req.url, error
+                    ),
+                    details: vec![],
+                });
+                self.emit(Event::DownloadFinished {
+                    url: req.url.clone(),
+                    content_hash: downloaded_hash,
+                    path: temp_file_path.clone(),
+                });
             }
         }
 
@@ -166,11 +257,18 @@ impl State {
     pub async fn git_checkout(&self, req: GitCheckoutRequest) -> anyhow::Result<GitCheckout> {
         let commit = self.lockfile.git_commit_hash(&req.repo, &req.git_ref).await;
         if let Some(ref commit) = commit {
+            // Checkouts from older versions still include the `.git` dir,
+            // so those get checked out again as a clean tree
             let existing_checkout_path = self.checkouts_dir.join(commit);
-            if existing_checkout_path.is_dir() {
+            if existing_checkout_path.is_dir() && !existing_checkout_path.join(".git").exists() {
+                let tree_hash = crate::hash::hash_dir(&existing_checkout_path).await?;
+                self.check_git_tree_hash(commit, tree_hash, &existing_checkout_path)
+                    .await?;
+
                 return Ok(GitCheckout {
                     checkout_path: existing_checkout_path,
                     commit: commit.to_string(),
+                    tree_hash,
                 });
             }
         }
@@ -198,17 +296,10 @@ impl State {
         let git_rev_parse_output = git_rev_parse_command.output().await?;
 
         if !git_rev_parse_output.status.success() {
-            println!(
-                "rev-parse stdout: {}",
-                String::from_utf8_lossy(&git_rev_parse_output.stdout)
-            );
-            eprintln!(
-                "rev-parse stderr: {}",
-                String::from_utf8_lossy(&git_rev_parse_output.stdout)
-            );
             anyhow::bail!(
-                "git rev-parse failed with exit code {})",
-                git_rev_parse_output.status
+                "git rev-parse failed with exit code {}: {}",
+                git_rev_parse_output.status,
+                String::from_utf8_lossy(&git_rev_parse_output.stderr).trim()
             );
         }
 
@@ -219,27 +310,39 @@ impl State {
         let git_commit_hash = hex::decode(git_commit_hash)?;
         let git_commit_hash = hex::encode(&git_commit_hash);
 
+        // Export the checkout as a clean tree, so only the tracked files
+        // contribute to the tree hash
+        fs::remove_dir_all(temp_checkout_path.join(".git")).await?;
+
+        let tree_hash = crate::hash::hash_dir(&temp_checkout_path).await?;
+        self.check_git_tree_hash(&git_commit_hash, tree_hash, &temp_checkout_path)
+            .await?;
+
         let final_checkout_path = self.checkouts_dir.join(&git_commit_hash);
         let _ = fs::remove_dir_all(&final_checkout_path).await;
 
         let rename_result = fs::rename(&temp_checkout_path, &final_checkout_path).await;
         match rename_result {
             Ok(()) => {
-                println!(
-                    "Checked out repo {} @ {} -> {}",
-                    req.repo,
-                    req.git_ref,
-                    final_checkout_path.display(),
-                );
+                self.emit(Event::CheckoutFinished {
+                    repo: req.repo.clone(),
+                    git_ref: req.git_ref.clone(),
+                    commit: git_commit_hash.clone(),
+                    path: final_checkout_path.clone(),
+                });
             }
             Err(error) => {
-                eprintln!(
-                    "Checked out repo {} @ {} -> {} (failed to rename: {})",
-                    req.repo,
-                    req.git_ref,
-                    final_checkout_path.display(),
-                    error,
-                );
+                self.emit(Event::Message {
+                    level: MessageLevel::Warning,
+                    message: format!(
+                        "failed to move checkout of {} @ {} to {}: {}",
+                        req.repo,
+                        req.git_ref,
+                        final_checkout_path.display(),
+                        error,
+                    ),
+                    details: vec![],
+                });
             }
         }
 
@@ -250,18 +353,42 @@ impl State {
         Ok(GitCheckout {
             checkout_path: final_checkout_path,
             commit: git_commit_hash,
+            tree_hash,
         })
     }
 
+    async fn check_git_tree_hash(
+        &self,
+        commit: &str,
+        tree_hash: Hash,
+        checkout_path: &Path,
+    ) -> anyhow::Result<()> {
+        match self.lockfile.git_tree_hash(commit).await {
+            Some(expected_hash) if expected_hash != tree_hash => {
+                anyhow::bail!(
+                    "Tree hash did not match for git commit {} at {} (expected {}, got {})",
+                    commit,
+                    checkout_path.display(),
+                    expected_hash,
+                    tree_hash,
+                );
+            }
+            Some(_) => {}
+            None => {
+                self.lockfile.set_git_tree_hash(commit, tree_hash).await;
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn unpack(
         &self,
         archive_tar_gz: &mut ContentFile,
         _unpack_opts: UnpackOpts,
     ) -> anyhow::Result<PathBuf> {
         let archive_dir = self
-            .project_dirs
-            .data_dir()
-            .join("unpack")
+            .unpack_dir
             .join(archive_tar_gz.content_hash.to_path_component());
         let temp_dir = archive_dir.join("temp");
         let unpacked_dir = archive_dir.join("unpacked");
@@ -287,20 +414,23 @@ impl State {
 
         match rename_result {
             Ok(()) => {
-                println!(
-                    "Unpacked {} -> {}",
-                    archive_tar_gz.content_hash,
-                    unpacked_dir.display()
-                );
+                self.emit(Event::Unpacked {
+                    content_hash: archive_tar_gz.content_hash,
+                    path: unpacked_dir.clone(),
+                });
                 Ok(unpacked_dir)
             }
             Err(error) => {
-                eprintln!(
-                    "Unpacked {} -> {} (failed to rename: {})",
-                    archive_tar_gz.content_hash,
-                    target_dir.display(),
-                    error
-                );
+                self.emit(Event::Message {
+                    level: MessageLevel::Warning,
+                    message: format!(
+                        "failed to move unpacked {} into place, using {}: {}",
+                        archive_tar_gz.content_hash,
+                        target_dir.display(),
+                        error
+                    ),
+                    details: vec![],
+                });
                 Ok(target_dir)
             }
         }
@@ -333,16 +463,19 @@ impl State {
         Ok(())
     }
 
+    /// The path a recipe's output prefix is saved to (whether or not the
+    /// recipe has been baked yet).
+    pub fn recipe_output_path(&self, recipe_ref: &ResolvedRecipeRef) -> PathBuf {
+        self.recipes_dir
+            .join(recipe_ref.to_path_component())
+            .join("prefix")
+    }
+
     pub fn get_recipe_output(
         &self,
         recipe_ref: &ResolvedRecipeRef,
     ) -> anyhow::Result<Option<PathBuf>> {
-        let recipe_prefix_dir = self
-            .project_dirs
-            .data_dir()
-            .join("recipes")
-            .join(recipe_ref.to_path_component())
-            .join("prefix");
+        let recipe_prefix_dir = self.recipe_output_path(recipe_ref);
 
         if recipe_prefix_dir.is_dir() {
             Ok(Some(recipe_prefix_dir))
@@ -351,21 +484,73 @@ impl State {
         }
     }
 
+    pub async fn get_recipe_metadata(
+        &self,
+        recipe_ref: &ResolvedRecipeRef,
+    ) -> anyhow::Result<Option<ResolvedRecipe>> {
+        let recipe_json_path = self
+            .recipes_dir
+            .join(recipe_ref.to_path_component())
+            .join("recipe.json");
+        read_json_file(&recipe_json_path).await
+    }
+
+    /// List the recipes with saved outputs.
+    pub async fn saved_recipe_refs(&self) -> anyhow::Result<Vec<ResolvedRecipeRef>> {
+        let mut recipe_refs = vec![];
+        let mut entries = fs::read_dir(&self.recipes_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let recipe_ref = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok());
+            if let Some(recipe_ref) = recipe_ref {
+                if self.get_recipe_output(&recipe_ref)?.is_some() {
+                    recipe_refs.push(recipe_ref);
+                }
+            }
+        }
+
+        recipe_refs.sort();
+        Ok(recipe_refs)
+    }
+
+    pub async fn get_build_metadata(
+        &self,
+        recipe_ref: &ResolvedRecipeRef,
+    ) -> anyhow::Result<Option<BuildMetadata>> {
+        let build_json_path = self
+            .recipes_dir
+            .join(recipe_ref.to_path_component())
+            .join("build.json");
+        read_json_file(&build_json_path).await
+    }
+
     pub async fn save_recipe_output(
         &self,
         recipe_ref: &crate::recipe::ResolvedRecipeRef,
+        recipe: &ResolvedRecipe,
+        mut build_metadata: BuildMetadata,
         output_dir: impl AsRef<Path>,
     ) -> anyhow::Result<PathBuf> {
-        let recipe_dir = self
-            .project_dirs
-            .data_dir()
-            .join("recipes")
-            .join(recipe_ref.to_path_component());
+        let recipe_dir = self.recipes_dir.join(recipe_ref.to_path_component());
 
         let recipe_prefix_dir = recipe_dir.join("prefix");
 
-        fs::create_dir_all(&recipe_prefix_dir).await?;
+        let content_hash = crate::hash::hash_output_dir(output_dir.as_ref()).await?;
+        build_metadata.content_hash = Some(content_hash);
+
+        // Write the recipe metadata before the prefix, so every saved prefix
+        // can be traced back to the recipe that produced it
+        fs::create_dir_all(&recipe_dir).await?;
+        let recipe_json = cjson::to_vec(recipe)
+            .map_err(|error| anyhow::anyhow!("failed to canonicalize JSON: {:?}", error))?;
+        fs::write(recipe_dir.join("recipe.json"), &recipe_json).await?;
+        let build_json = serde_json::to_vec_pretty(&build_metadata)?;
+        fs::write(recipe_dir.join("build.json"), &build_json).await?;
 
+        // The prefix only shows up once it's complete, so a bake that gets
+        // interrupted never looks like it finished
         let temp_id = Uuid::new_v4();
         let recipe_prefix_temp_dir = recipe_dir.join(format!("prefix-tmp.{}", temp_id));
 
@@ -384,20 +569,37 @@ impl State {
         Ok(recipe_prefix_dir)
     }
 
-    // pub async fn get_recipe_aux(
-    //     &self,
-    //     recipe_ref: &crate::recipe::ResolvedRecipeRef,
-    // ) -> Option<RecipeAux> {
-    //     self.lockfile.recipe_aux(recipe_ref).await
-    // }
+    pub async fn locked_content_hashes(&self) -> HashSet<Hash> {
+        self.lockfile.request_hashes().await
+    }
 
-    pub async fn set_recipe_aux(
-        &self,
-        recipe_ref: &crate::recipe::ResolvedRecipeRef,
-        recipe_aux: RecipeAux,
-    ) {
-        self.lockfile.set_recipe_aux(recipe_ref, recipe_aux).await;
+    pub async fn locked_git_commits(&self) -> HashSet<String> {
+        self.lockfile.git_commits().await
     }
+
+    /// Get the tree hashes of the locked git commits.
+    pub async fn locked_git_tree_hashes(&self) -> HashSet<Hash> {
+        self.lockfile.git_tree_hashes().await
+    }
+}
+
+async fn read_json_file<T>(path: &Path) -> anyhow::Result<Option<T>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let content = match fs::read(path).await {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        Err(error) => {
+            return Err(error.into());
+        }
+    };
+
+    let value = serde_json::from_slice(&content)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    Ok(Some(value))
 }
 
 pub struct GitCheckoutRequest {
@@ -417,6 +619,7 @@ impl GitCheckoutRequest {
 #[derive(Debug)]
 pub struct GitCheckout {
     pub commit: String,
+    pub tree_hash: Hash,
     pub checkout_path: PathBuf,
 }
 
@@ -515,15 +718,36 @@ impl Lockfile {
         repo_commits.insert(git_ref.to_string(), commit.to_string());
     }
 
-    // async fn recipe_aux(&self, recipe_ref: &ResolvedRecipeRef) -> Option<RecipeAux> {
-    //     let lock = self.current_value.read().await;
-    //     let recipe_aux = lock.recipe_aux.get(recipe_ref);
-    //     recipe_aux.cloned()
-    // }
+    async fn git_tree_hash(&self, commit: &str) -> Option<Hash> {
+        let lock = self.current_value.read().await;
+        lock.git_tree_hashes.get(commit).cloned()
+    }
 
-    async fn set_recipe_aux(&self, recipe_ref: &ResolvedRecipeRef, recipe_aux: RecipeAux) {
+    async fn set_git_tree_hash(&self, commit: &str, tree_hash: Hash) {
         let mut lock = self.current_value.write().await;
-        lock.recipe_aux.insert(*recipe_ref, recipe_aux);
+        lock.git_tree_hashes.insert(commit.to_string(), tree_hash);
+    }
+
+    async fn request_hashes(&self) -> HashSet<Hash> {
+        let lock = self.current_value.read().await;
+        lock.request_hashes.values().cloned().collect()
+    }
+
+    async fn git_commits(&self) -> HashSet<String> {
+        let lock = self.current_value.read().await;
+        lock.git_commits
+            .values()
+            .flat_map(|repo_commits| repo_commits.values().cloned())
+            .collect()
+    }
+
+    async fn git_tree_hashes(&self) -> HashSet<Hash> {
+        let lock = self.current_value.read().await;
+        lock.git_commits
+            .values()
+            .flat_map(|repo_commits| repo_commits.values())
+            .filter_map(|commit| lock.git_tree_hashes.get(commit).cloned())
+            .collect()
     }
 
     async fn persist(&self) -> anyhow::Result<bool> {
@@ -536,8 +760,13 @@ impl Lockfile {
         } else {
             let new_content = serde_json::to_vec_pretty(&*current_value)?;
 
-            let mut file = fs::File::create(&self.path).await?;
+            // Write to a temporary file first, so the lockfile is never left
+            // half-written if brioche gets interrupted
+            let temp_path = self.path.with_extension("tmp");
+            let mut file = fs::File::create(&temp_path).await?;
             tokio::io::copy(&mut &new_content[..], &mut file).await?;
+            file.sync_all().await?;
+            fs::rename(&temp_path, &self.path).await?;
 
             *persisted_value = current_value.clone();
             Ok(true)
@@ -549,11 +778,66 @@ impl Lockfile {
 struct ContentLock {
     request_hashes: HashMap<Url, Hash>,
     git_commits: HashMap<Url, HashMap<String, String>>,
-    recipe_aux: HashMap<ResolvedRecipeRef, RecipeAux>,
+    #[serde(default)]
+    git_tree_hashes: HashMap<String, Hash>,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+/// Details about how a recipe was baked, stored next to the recipe's output.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildMetadata {
+    pub baked_at: std::time::SystemTime,
+    /// Content hash of the saved prefix (see `crate::hash::hash_output_dir`). This
+    /// gets filled in by `State::save_recipe_output`.
+    #[serde(default)]
+    pub content_hash: Option<Hash>,
+    /// Stats about the build. Missing for recipes baked by older versions.
+    #[serde(default)]
+    pub aux: Option<RecipeAux>,
+}
+
+/// A work dir from `State::new_temp_work_dir`, which stays locked until
+/// this is dropped so GC can tell it's in use.
+#[derive(Debug)]
+pub struct TempWorkDir {
+    pub path: PathBuf,
+    lock_path: PathBuf,
+    _lock_file: std::fs::File,
+}
+
+impl Drop for TempWorkDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.lock_path);
+    }
+}
+
+/// The lock file next to a work dir's parent dir from
+/// `State::new_temp_work_dir`.
+pub fn work_dir_lock_path(dir: &Path) -> PathBuf {
+    dir.with_extension("lock")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct RecipeAux {
     pub lines_stdout: u64,
     pub lines_stderr: u64,
+    pub wall_time: std::time::Duration,
+    /// User and system CPU time of the build script and the processes it
+    /// waited for.
+    pub cpu_time: std::time::Duration,
+    /// Peak resident memory of the largest single process in the build
+    /// (stored as `peakMemoryBytes` by older versions).
+    #[serde(alias = "peakMemoryBytes")]
+    pub max_process_rss_bytes: u64,
+    /// Peak memory of the whole build, when it ran in a cgroup with a memory
+    /// limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cgroup_peak_memory_bytes: Option<u64>,
+    /// Total size of the files in the saved prefix.
+    pub output_size_bytes: u64,
+    /// Number of files and symlinks in the saved prefix.
+    pub output_file_count: u64,
+    pub host_triple: String,
+    pub brioche_version: String,
 }
@@ -0,0 +1,82 @@
+use crate::{
+    event::{Event, MessageLevel},
+    recipe::ResolvedRecipeRef,
+    state::State,
+};
+
+/// Re-hash the saved prefixes of the given recipes (or every saved recipe
+/// if none are given), and compare them against the content hashes recorded
+/// when they were baked.
+pub async fn verify(state: &State, recipe_refs: &[ResolvedRecipeRef]) -> anyhow::Result<()> {
+    let recipe_refs = if recipe_refs.is_empty() {
+        state.saved_recipe_refs().await?
+    } else {
+        recipe_refs.to_vec()
+    };
+
+    let mut num_verified = 0;
+    let mut num_skipped = 0;
+    let mut failed_refs = vec![];
+    for recipe_ref in &recipe_refs {
+        let prefix_path = match state.get_recipe_output(recipe_ref)? {
+            Some(prefix_path) => prefix_path,
+            None => {
+                anyhow::bail!("recipe {} has not been baked", recipe_ref);
+            }
+        };
+
+        let expected_hash = state
+            .get_build_metadata(recipe_ref)
+            .await?
+            .and_then(|build_metadata| build_metadata.content_hash);
+        let expected_hash = match expected_hash {
+            Some(expected_hash) => expected_hash,
+            None => {
+                state.emit(Event::Message {
+                    level: MessageLevel::Info,
+                    message: format!("{}: skipped (no content hash recorded)", recipe_ref),
+                    details: vec![],
+                });
+                num_skipped += 1;
+                continue;
+            }
+        };
+
+        let actual_hash = crate::hash::hash_output_dir(&prefix_path).await?;
+        if actual_hash == expected_hash {
+            state.emit(Event::Message {
+                level: MessageLevel::Info,
+                message: format!("{}: ok", recipe_ref),
+                details: vec![],
+            });
+            num_verified += 1;
+        } else {
+            state.emit(Event::Message {
+                level: MessageLevel::Warning,
+                message: format!(
+                    "{}: content hash mismatch (expected {}, got {})",
+                    recipe_ref, expected_hash, actual_hash
+                ),
+                details: vec![],
+            });
+            failed_refs.push(*recipe_ref);
+        }
+    }
+
+    state.emit(Event::Message {
+        level: MessageLevel::Info,
+        message: format!(
+            "Verified {} recipes ({} failed, {} skipped)",
+            num_verified,
+            failed_refs.len(),
+            num_skipped
+        ),
+        details: vec![],
+    });
+
+    if !failed_refs.is_empty() {
+        anyhow::bail!("{} recipes failed verification", failed_refs.len());
+    }
+
+    Ok(())
+}